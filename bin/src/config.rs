@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::time::Duration;
 
+use restate_downloader::config::Config as DownloaderConfig;
 use restate_sdk::prelude::{HandlerOptions, ServiceOptions};
 use serde::{Deserialize, Serialize};
 use url::Url;
@@ -12,6 +13,9 @@ pub struct Settings {
 
     #[serde(default)]
     pub store: StoreConfig,
+
+    #[serde(default)]
+    pub downloader: DownloaderConfig,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
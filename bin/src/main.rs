@@ -3,8 +3,11 @@ mod config;
 use figment::{Figment, providers::Env};
 use opendal::Operator;
 use opendal::layers::LoggingLayer;
+use restate_downloader::progress::{DownloadProgress, DownloadProgressImpl};
+use restate_downloader::with_store::DownloadWorkflow as DownloadWorkflowWithStore;
 use restate_downloader::with_store::Downloader as DownloaderWithStore;
 use restate_downloader::with_store::DownloaderImpl as DownloaderWithStoreImpl;
+use restate_downloader::without_store::DownloadWorkflow as DownloadWorkflowWithoutStore;
 use restate_downloader::without_store::Downloader as DownloaderWithoutStore;
 use restate_downloader::without_store::DownloaderImpl as DownloaderWithoutStoreImpl;
 use restate_sdk::{endpoint::Endpoint, http_server::HttpServer};
@@ -28,9 +31,12 @@ async fn main() {
 
     let bind_addr = format!("0.0.0.0:{}", port);
 
-    let client = reqwest::Client::builder()
+    // Downloads and webhooks go to caller provided URLs, so the client enforces the egress policy
+    let client = settings
+        .downloader
+        .egress
+        .client_builder()
         // .user_agent(&config.user_agent)
-        // .redirect(reqwest::redirect::Policy::limited(config.max_redirects))
         // .timeout(Duration::from_secs(if timeout > 0 {
         //     timeout
         // } else {
@@ -39,19 +45,36 @@ async fn main() {
         .build()
         .unwrap();
 
-    let mut endpoint = Endpoint::builder();
+    // Progress is reported to the Restate ingress, which is usually not a public address
+    let ingress_client = reqwest::Client::new();
+
+    let mut endpoint = Endpoint::builder().bind(DownloadProgressImpl.serve());
 
     if let Some(store_url) = settings.store.uri {
         let operator = Operator::from_uri(store_url.to_string())
             .unwrap()
             .layer(LoggingLayer::default());
-        let service = DownloaderWithStoreImpl::new(client, operator);
+        let service = DownloaderWithStoreImpl::new(client, operator)
+            .with_ingress_client(ingress_client)
+            .with_config(settings.downloader);
 
-        endpoint = endpoint.bind_with_options(service.serve(), settings.restate.service.into())
+        endpoint = endpoint
+            .bind_with_options(
+                DownloaderWithStore::serve(service.clone()),
+                settings.restate.service.into(),
+            )
+            .bind(DownloadWorkflowWithStore::serve(service))
     } else {
-        let service = DownloaderWithoutStoreImpl::new(client);
+        let service = DownloaderWithoutStoreImpl::new(client)
+            .with_ingress_client(ingress_client)
+            .with_config(settings.downloader);
 
-        endpoint = endpoint.bind_with_options(service.serve(), settings.restate.service.into())
+        endpoint = endpoint
+            .bind_with_options(
+                DownloaderWithoutStore::serve(service.clone()),
+                settings.restate.service.into(),
+            )
+            .bind(DownloadWorkflowWithoutStore::serve(service))
     }
 
     // Create and start the HTTP server
@@ -1,18 +1,48 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::Duration,
+};
 
 use anyhow::{Context as _, Result};
 use content_disposition::parse_content_disposition;
-use futures::{Stream, StreamExt as _};
-use opendal::{Operator, Writer};
+use futures::{Stream, StreamExt as _, TryStreamExt as _, stream::BoxStream};
+use opendal::{Metadata, Operator, Writer};
 use reqwest::{
-    Response,
-    header::{HeaderMap, HeaderName, HeaderValue},
+    Response, StatusCode,
+    header::{
+        CONTENT_TYPE, ETAG, HeaderMap, HeaderName, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED,
+    },
+};
+use restate_sdk::{
+    context::{
+        Context, ContextClient as _, ContextPromises as _, ContextSideEffects as _,
+        SharedWorkflowContext, WorkflowContext,
+    },
+    errors::{HandlerError, TerminalError},
+    serde::Json,
 };
-use restate_sdk::errors::{HandlerError, TerminalError};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::{
+    compression::{Compression, Encoder, content_encodings, decompressed_filename},
+    config::Config,
+    digest::{Checksum, DigestAlgorithm, Digester},
+    egress::{EgressPolicy, egress_error},
+    extract::{Extractor, detect_archive, extract_file},
+    filename::{FilenamePolicy, Source},
+    notify::Notifications,
+    progress::{
+        DEFAULT_PROGRESS_INTERVAL, DownloadProgressClient, DownloadStatus, ProgressReporter,
+        cancelled, report_finished, report_started,
+    },
+    resume::{DEFAULT_MAX_RESUME_ATTEMPTS, resumable_stream},
+    segment::{Segmentation, segmented_stream},
+    sidecar::{SIDECAR_SUFFIX, Sidecar},
+};
+
 #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct RequestOptions {
@@ -23,6 +53,23 @@ pub struct RequestOptions {
     #[serde(default, with = "humantime_serde")]
     #[schemars(with = "Option<String>")]
     pub timeout: Option<Duration>,
+    /// Maximum number of attempts to resume an interrupted download using range requests (defaults to 3, 0 disables resuming)
+    ///
+    /// The download is only resumed within a single attempt of the download step: the received bytes are not
+    /// journaled, so a retried step (eg. after a restart of the service) downloads the file from the start again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_resume_attempts: Option<u32>,
+    /// Size of a segment in bytes for parallel segmented downloads (falls back to the service default, capped at the service maximum)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = 1))]
+    pub segment_size: Option<u64>,
+    /// Number of segments downloaded concurrently when the server supports byte ranges (falls back to the service default, capped at the service maximum)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = 1))]
+    pub concurrency: Option<usize>,
+    /// Maximum size of the downloaded file in bytes (falls back to the service default)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
 }
 
 impl TryFrom<RequestOptions> for HeaderMap {
@@ -42,7 +89,12 @@ impl TryFrom<RequestOptions> for HeaderMap {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+/// Options for the stored file
+///
+/// Options the storage cannot apply to the written file fail the download instead of being ignored.
+/// The storage class and object tags cannot be set per file, as the storage layer has no write options for them
+/// (the storage class of S3 and GCS can be set for all files with the `default_storage_class` option of the storage).
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct OutputOptions {
     /// Set the content type of the downloaded file
@@ -51,16 +103,438 @@ pub struct OutputOptions {
     /// Content type override for the downloaded file (falls back to the content type of the downloaded file)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content_type: Option<String>,
+    /// Content disposition of the stored file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_disposition: Option<String>,
+    /// Cache control of the stored file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<String>,
+    /// Content encoding of the stored file (overrides the encoding of the compress option)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    /// User metadata of the stored file
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    /// Response headers copied into the user metadata of the stored file (eg. "etag", "last-modified")
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub metadata_headers: Vec<String>,
+    /// Write the file to a staging path first and move it to the final path once the download succeeded
+    /// (extracted files are moved once the whole archive is verified)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub staged: bool,
+    /// Prefix of the staging path relative to the root prefix and tenant directory of the service (falls back to the service default)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub staging_prefix: Option<String>,
+    /// Skip the download if the source did not change since it was last downloaded to the same path (the output must name a file)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub only_if_changed: bool,
+    /// What to do when a file already exists at the target path
+    #[serde(default, skip_serializing_if = "OnExists::is_overwrite")]
+    pub on_exists: OnExists,
+    /// Extract the downloaded archive (zip, tar, tar.gz, tar.zst or tar.xz) into the directory of the target path
+    ///
+    /// Extraction is not atomic: files are written while the archive is downloaded, so without staged
+    /// they are visible before the archive is verified and are only deleted on a best-effort basis if it fails.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub extract: bool,
+    /// Decompress a single-file gzip, zstd, bzip2 or xz payload and strip the compression extension from the filename (eg. "dump.sql.gz" -> "dump.sql")
+    ///
+    /// The size, digests and checksum describe the downloaded payload, so the checksum published for "dump.sql.gz" still applies.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub decompress: bool,
+    /// Compress the stored file while streaming and append the extension of the algorithm to the path (the size and digests describe the downloaded file)
+    ///
+    /// The Content-Encoding of the stored file is set to the algorithm, so like contentEncoding it requires a storage supporting it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compress: Option<CompressOptions>,
+    /// Write a `<path>.meta.json` sidecar recording where the file came from next to the downloaded file
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sidecar: bool,
+}
+
+impl OutputOptions {
+    /// Adjust a filename determined from the source to the file that is stored
+    pub(crate) fn filename(&self, filename: String) -> String {
+        if self.decompress && !self.extract {
+            return decompressed_filename(filename);
+        }
+
+        filename
+    }
+
+    /// Adjust a resolved path to the file that is stored
+    pub(crate) fn path(&self, path: String) -> String {
+        match &self.compress {
+            Some(compress) if !path.ends_with(compress.algorithm.extension()) => {
+                format!("{}{}", path, compress.algorithm.extension())
+            }
+            _ => path,
+        }
+    }
+
+    /// Check whether the target has to be inspected before the request is sent
+    pub(crate) fn needs_precheck(&self) -> bool {
+        self.only_if_changed || matches!(self.on_exists, OnExists::Skip | OnExists::Fail)
+    }
+}
+
+/// Policy for handling files that already exist at the target path
+///
+/// Files are stamped with a token of the download, so a retried download step recognises the file written by
+/// its previous attempt as its own output and overwrites it (on stores without user metadata it is treated as
+/// any other existing file).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OnExists {
+    /// Overwrite the existing file
+    #[default]
+    Overwrite,
+    /// Keep the existing file and skip the download
+    Skip,
+    /// Fail the download
+    Fail,
+    /// Append a numeric suffix to the filename until it does not collide with an existing file
+    Rename,
+}
+
+impl OnExists {
+    fn is_overwrite(&self) -> bool {
+        *self == OnExists::Overwrite
+    }
+}
+
+/// Compression of the stored file
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CompressOptions {
+    /// Compression algorithm
+    pub algorithm: CompressionAlgorithm,
+    /// Compression level (gzip: 0-9, zstd: 1-22, defaults to the default level of the algorithm)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>,
+}
+
+/// Algorithm used to compress stored files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    Gzip,
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    /// Extension appended to the path of the stored file
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Gzip => ".gz",
+            CompressionAlgorithm::Zstd => ".zst",
+        }
+    }
+
+    /// Value of the Content-Encoding metadata of the stored file
+    pub fn content_encoding(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Gzip => "gzip",
+            CompressionAlgorithm::Zstd => "zstd",
+        }
+    }
 }
 
 /// Response from the download operation
-#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct DownloadResponse {
-    /// Path to the downloaded file
+    /// Path to the downloaded file (or the directory the archive was extracted to)
     pub path: String,
     /// Size of the downloaded file
     pub size: u64,
+    /// Hex encoded digests of the downloaded file by algorithm
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub digests: BTreeMap<DigestAlgorithm, String>,
+    /// The file was not downloaded because the source did not change since the last download
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unchanged: bool,
+    /// The file was not downloaded because a file already exists at the target path
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+    /// Files extracted from the downloaded archive
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entries: Vec<ExtractedEntry>,
+}
+
+/// File extracted from an archive
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtractedEntry {
+    /// Path to the extracted file
+    pub path: String,
+    /// Size of the extracted file
+    pub size: u64,
+}
+
+impl DownloadResponse {
+    fn skipped(path: &str, metadata: &Metadata) -> Self {
+        Self {
+            path: path.to_string(),
+            size: metadata.content_length(),
+            digests: BTreeMap::new(),
+            unchanged: false,
+            skipped: true,
+            entries: Vec::new(),
+        }
+    }
+
+    fn unchanged(path: &str, metadata: &Metadata) -> Self {
+        Self {
+            path: path.to_string(),
+            size: metadata.content_length(),
+            digests: BTreeMap::new(),
+            unchanged: true,
+            skipped: false,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// Options for processing a download
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    /// Download ID used to report progress
+    pub id: Option<String>,
+    /// URL the download was requested from (recorded in the sidecar)
+    pub url: Option<Url>,
+    /// Directory the target path is confined to, ending with a slash (staged files are written under it)
+    pub root: Option<String>,
+    /// Request options (used when the download needs to be resumed)
+    pub request: Option<RequestOptions>,
+    /// Output options
+    pub output: Option<OutputOptions>,
+    /// Expected checksum of the downloaded file
+    pub checksum: Option<Checksum>,
+    /// Digest algorithms to compute for the downloaded file
+    pub digests: Vec<DigestAlgorithm>,
+    /// Token identifying the download across retries of its step (stored with the file, see [`OnExists`])
+    pub token: Option<String>,
+}
+
+/// Request to download multiple files
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadBatchRequest<T> {
+    /// Files to download
+    pub downloads: Vec<T>,
+    /// Maximum number of downloads running at the same time (falls back to the service default)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = 1))]
+    pub concurrency: Option<usize>,
+}
+
+/// Response from the batch download operation
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadBatchResponse {
+    /// Result of each download in the same order as the requested downloads
+    pub results: Vec<DownloadResult>,
+}
+
+/// Result of a single download in a batch
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum DownloadResult {
+    /// The download succeeded
+    Succeeded(DownloadResponse),
+    /// The download failed
+    Failed {
+        /// Reason of the failure
+        error: String,
+    },
+}
+
+impl From<Result<Json<DownloadResponse>, TerminalError>> for DownloadResult {
+    fn from(result: Result<Json<DownloadResponse>, TerminalError>) -> Self {
+        match result {
+            Ok(response) => DownloadResult::Succeeded(response.into_inner()),
+            Err(err) => DownloadResult::Failed {
+                error: err.message().to_string(),
+            },
+        }
+    }
+}
+
+/// Default number of downloads running at the same time in a batch
+const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+
+/// Default upper bound of the number of downloads running at the same time in a batch
+const DEFAULT_MAX_BATCH_CONCURRENCY: usize = 32;
+
+/// Determine the number of downloads running at the same time in a batch,
+/// capping the requested value at the service maximum
+pub(crate) fn batch_concurrency(requested: Option<usize>, config: &Config) -> usize {
+    requested
+        .or(config.batch_concurrency)
+        .unwrap_or(DEFAULT_BATCH_CONCURRENCY)
+        .min(
+            config
+                .max_batch_concurrency
+                .unwrap_or(DEFAULT_MAX_BATCH_CONCURRENCY),
+        )
+}
+
+/// Run a batch of downloads with bounded concurrency.
+///
+/// Downloads are started in order and awaited in the same order, so the journal stays deterministic.
+/// Once `concurrency` downloads are in flight, the oldest one is awaited before starting the next one,
+/// so a slow download holds back the ones queued behind it even when later downloads already finished.
+pub(crate) async fn run_batch<T, F, Fut>(
+    downloads: Vec<T>,
+    concurrency: usize,
+    mut download: F,
+) -> Vec<DownloadResult>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = Result<Json<DownloadResponse>, TerminalError>>,
+{
+    let concurrency = concurrency.clamp(1, downloads.len().max(1));
+    let mut results = Vec::with_capacity(downloads.len());
+    let mut pending: VecDeque<Fut> = VecDeque::with_capacity(concurrency);
+
+    for request in downloads {
+        if pending.len() >= concurrency
+            && let Some(oldest) = pending.pop_front()
+        {
+            results.push(oldest.await.into());
+        }
+
+        pending.push_back(download(request));
+    }
+
+    for future in pending {
+        results.push(future.await.into());
+    }
+
+    results
+}
+
+/// Name of the durable promise a download workflow completes with the result of the download
+pub(crate) const COMPLETION_PROMISE: &str = "completion";
+
+/// Run a download in a journaled step, then record its outcome and send its notifications
+///
+/// The download is cancellable when it reports its progress through the Restate ingress.
+pub(crate) async fn run_download(
+    ctx: &Context<'_>,
+    id: Option<&str>,
+    cancellable: bool,
+    notifications: Notifications,
+    download: impl Future<Output = Result<DownloadResponse, HandlerError>> + Send,
+) -> Result<Json<DownloadResponse>, HandlerError> {
+    report_started(ctx, id, cancellable);
+
+    let result = ctx.run(async || download.await.map(Json)).await;
+
+    report_finished(ctx, id, &result);
+    if let Some(notify) = notifications.send(ctx, &result)
+        && let Err(err) = ctx.run(notify).await
+    {
+        tracing::warn!(error = %err, "Failed to send download notification");
+    }
+
+    Ok(result?)
+}
+
+/// Run the download of a workflow like [`run_download`], completing the workflow with its result
+pub(crate) async fn run_workflow(
+    ctx: &WorkflowContext<'_>,
+    cancellable: bool,
+    notifications: Notifications,
+    download: impl Future<Output = Result<DownloadResponse, HandlerError>> + Send,
+) -> Result<Json<DownloadResponse>, HandlerError> {
+    let id = Some(ctx.key());
+
+    report_started(ctx, id, cancellable);
+
+    let result = ctx.run(async || download.await.map(Json)).await;
+
+    report_finished(ctx, id, &result);
+    complete_workflow(ctx, &result);
+
+    if let Some(notify) = notifications.send(ctx, &result)
+        && let Err(err) = ctx.run(notify).await
+    {
+        tracing::warn!(error = %err, "Failed to send download notification");
+    }
+
+    Ok(result?)
+}
+
+/// Completion promise of a download workflow (implemented by the workflow context)
+pub(crate) trait CompletionPromise {
+    fn resolve(&self, response: Json<DownloadResponse>);
+
+    fn reject(&self, error: TerminalError);
+}
+
+impl CompletionPromise for WorkflowContext<'_> {
+    fn resolve(&self, response: Json<DownloadResponse>) {
+        self.resolve_promise(COMPLETION_PROMISE, response);
+    }
+
+    fn reject(&self, error: TerminalError) {
+        self.reject_promise(COMPLETION_PROMISE, error);
+    }
+}
+
+/// Complete the promise of a workflow with the result of its download, so callers awaiting it are released
+fn complete_workflow(
+    promise: &impl CompletionPromise,
+    result: &Result<Json<DownloadResponse>, TerminalError>,
+) {
+    match result {
+        Ok(response) => promise.resolve(Json(response.0.clone())),
+        Err(err) => promise.reject(err.clone()),
+    }
+}
+
+/// Get the status of the download of a workflow from the progress object keyed by the workflow ID
+pub(crate) async fn workflow_status(
+    ctx: &SharedWorkflowContext<'_>,
+) -> Result<Json<DownloadStatus>, HandlerError> {
+    Ok(ctx
+        .object_client::<DownloadProgressClient>(ctx.key())
+        .get_status()
+        .call()
+        .await?)
+}
+
+/// Wait for a download workflow to complete
+pub(crate) async fn await_workflow(
+    ctx: &SharedWorkflowContext<'_>,
+) -> Result<Json<DownloadResponse>, HandlerError> {
+    Ok(ctx.promise(COMPLETION_PROMISE).await?)
+}
+
+/// Cancel the download of a workflow through the progress object keyed by the workflow ID
+pub(crate) async fn cancel_workflow(
+    ctx: &SharedWorkflowContext<'_>,
+) -> Result<Json<DownloadStatus>, HandlerError> {
+    Ok(ctx
+        .object_client::<DownloadProgressClient>(ctx.key())
+        .cancel()
+        .call()
+        .await?)
+}
+
+/// Result of streaming a file to storage
+#[derive(Debug)]
+pub struct StreamedFile {
+    /// Path the file was written to (or the directory the archive was extracted to)
+    pub path: String,
+    /// Number of bytes written
+    pub size: u64,
+    /// Hex encoded digests of the written bytes by algorithm
+    pub digests: BTreeMap<DigestAlgorithm, String>,
+    /// Files extracted from the archive
+    pub entries: Vec<ExtractedEntry>,
 }
 
 pub(crate) fn create_request(
@@ -84,21 +558,34 @@ pub(crate) fn create_request(
 
 pub(crate) async fn send_request(
     client: &reqwest::Client,
+    egress: &EgressPolicy,
     url: Url,
     options: Option<RequestOptions>,
+    headers: HeaderMap,
 ) -> Result<reqwest::Response, HandlerError> {
+    egress.check_url(&url).map_err(terminal)?;
+
     create_request(client, url, options)
         .map_err(terminal)?
+        .headers(headers)
         .send()
-        .await?
+        .await
+        .map_err(http_error)?
         .error_for_status()
         .map_err(http_error)
 }
 
-pub(crate) fn filename_from_response(response: &Response) -> Result<String> {
-    filename_from_headers(response.headers())
-        .or_else(|| filename_from_url(response.url()))
-        .context("Failed to determine filename from the response")
+/// Determine the sanitized filename from the Content-Disposition header or the final URL of the response
+pub(crate) fn filename_from_response(
+    response: &Response,
+    policy: &FilenamePolicy,
+) -> Result<String, HandlerError> {
+    let url = response.url();
+
+    match filename_from_headers(response.headers()) {
+        Some(filename) => policy.sanitize(Some(&filename), Source::Header, url.as_str()),
+        None => policy.sanitize(filename_from_url(url).as_deref(), Source::Url, url.as_str()),
+    }
 }
 
 fn filename_from_url(url: &Url) -> Option<String> {
@@ -114,17 +601,223 @@ fn filename_from_headers(headers: &HeaderMap) -> Option<String> {
         .and_then(|cd| parse_content_disposition(cd).filename_full())
 }
 
+/// User metadata keys used to remember the validators of the downloaded source
+const SOURCE_ETAG_METADATA: &str = "source-etag";
+const SOURCE_LAST_MODIFIED_METADATA: &str = "source-last-modified";
+
+/// User metadata key of the token of the download that wrote the file
+const DOWNLOAD_TOKEN_METADATA: &str = "download-token";
+
+/// Result of inspecting the target before the request is sent
+pub(crate) enum Precheck {
+    /// Send the request with the given (conditional) headers
+    Send(HeaderMap),
+    /// Skip the download entirely
+    Skip(DownloadResponse),
+}
+
+/// Inspect the target path before the request is sent.
+///
+/// Existing files are skipped or rejected according to the [`OnExists`] policy,
+/// and conditional request headers are built from the validators stored with a previously downloaded file.
+///
+/// The path is `None` when the output names a directory, as the filename is only known once the response is available.
+/// Nothing is inspected then (the final path is checked by [`check_target`]) and `onlyIfChanged` is rejected,
+/// since the validators of the previously downloaded file cannot be looked up.
+///
+/// A file written by a previous attempt of the download with the given token is downloaded again.
+pub(crate) async fn precheck(
+    operator: &Operator,
+    path: Option<&str>,
+    output: &OutputOptions,
+    token: Option<&str>,
+) -> Result<Precheck, HandlerError> {
+    let mut headers = HeaderMap::new();
+
+    let Some(path) = path else {
+        if output.only_if_changed {
+            return Err(terminal(
+                "onlyIfChanged requires the output to name a file, not a directory",
+            ));
+        }
+
+        return Ok(Precheck::Send(headers));
+    };
+
+    let Some(metadata) = stat_target(operator, path).await? else {
+        return Ok(Precheck::Send(headers));
+    };
+
+    if written_by(&metadata, token) {
+        return Ok(Precheck::Send(headers));
+    }
+
+    match output.on_exists {
+        OnExists::Skip => return Ok(Precheck::Skip(DownloadResponse::skipped(path, &metadata))),
+        OnExists::Fail => return Err(already_exists(path)),
+        OnExists::Overwrite | OnExists::Rename => {}
+    }
+
+    if !output.only_if_changed {
+        return Ok(Precheck::Send(headers));
+    }
+
+    let Some(user_metadata) = metadata.user_metadata() else {
+        return Ok(Precheck::Send(headers));
+    };
+
+    if let Some(etag) = user_metadata.get(SOURCE_ETAG_METADATA)
+        && let Ok(value) = HeaderValue::from_str(etag)
+    {
+        headers.insert(IF_NONE_MATCH, value);
+    }
+
+    if let Some(last_modified) = user_metadata.get(SOURCE_LAST_MODIFIED_METADATA)
+        && let Ok(value) = HeaderValue::from_str(last_modified)
+    {
+        headers.insert(IF_MODIFIED_SINCE, value);
+    }
+
+    Ok(Precheck::Send(headers))
+}
+
+/// Result of checking the final target path against existing files
+pub(crate) enum Target {
+    /// Write the file to the given path
+    Write(String),
+    /// Skip the download
+    Skip(DownloadResponse),
+}
+
+/// Check the final target path once the response is available.
+///
+/// A `304 Not Modified` response keeps the existing file,
+/// otherwise the [`OnExists`] policy is applied to files not written by the download with the given token.
+pub(crate) async fn check_target(
+    operator: &Operator,
+    response: &Response,
+    path: String,
+    on_exists: OnExists,
+    token: Option<&str>,
+) -> Result<Target, HandlerError> {
+    if response.status() == StatusCode::NOT_MODIFIED {
+        let metadata = stat_target(operator, &path).await?.ok_or_else(|| {
+            terminal(format!(
+                "Source is not modified, but no file exists at the target path: {}",
+                path
+            ))
+        })?;
+
+        return Ok(Target::Skip(DownloadResponse::unchanged(&path, &metadata)));
+    }
+
+    if on_exists == OnExists::Overwrite {
+        return Ok(Target::Write(path));
+    }
+
+    let Some(metadata) = stat_target(operator, &path).await? else {
+        return Ok(Target::Write(path));
+    };
+
+    // A previous attempt of the download step already wrote the file
+    if written_by(&metadata, token) {
+        return Ok(Target::Write(path));
+    }
+
+    match on_exists {
+        OnExists::Overwrite => Ok(Target::Write(path)),
+        OnExists::Skip => Ok(Target::Skip(DownloadResponse::skipped(&path, &metadata))),
+        OnExists::Fail => Err(already_exists(&path)),
+        OnExists::Rename => {
+            for n in 1..=MAX_RENAME_ATTEMPTS {
+                let candidate = with_numeric_suffix(&path, n);
+
+                match stat_target(operator, &candidate).await? {
+                    Some(metadata) if !written_by(&metadata, token) => {}
+                    _ => return Ok(Target::Write(candidate)),
+                }
+            }
+
+            Err(terminal(format!(
+                "Failed to find a free path for {} after {} attempts",
+                path, MAX_RENAME_ATTEMPTS
+            )))
+        }
+    }
+}
+
+const MAX_RENAME_ATTEMPTS: u32 = 1000;
+
+fn written_by(metadata: &Metadata, token: Option<&str>) -> bool {
+    token.is_some_and(|token| {
+        metadata
+            .user_metadata()
+            .and_then(|m| m.get(DOWNLOAD_TOKEN_METADATA))
+            .is_some_and(|written| written == token)
+    })
+}
+
+async fn stat_target(operator: &Operator, path: &str) -> Result<Option<Metadata>, HandlerError> {
+    match operator.stat(path).await {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(err) if err.kind() == opendal::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(anyhow::Error::new(err)
+            .context("Failed to stat target path")
+            .into()),
+    }
+}
+
+fn already_exists(path: &str) -> HandlerError {
+    terminal(format!(
+        "A file already exists at the target path: {}",
+        path
+    ))
+}
+
+/// Insert a numeric suffix before the extension(s) of the filename (eg. "dir/file.tar.gz" -> "dir/file-1.tar.gz")
+fn with_numeric_suffix(path: &str, n: u32) -> String {
+    let (dir, filename) = match path.rfind('/') {
+        Some(i) => path.split_at(i + 1),
+        None => ("", path),
+    };
+
+    // Leading dots belong to the name of hidden files, not to the extension
+    let name_start = filename.len() - filename.trim_start_matches('.').len();
+
+    match filename[name_start..].find('.') {
+        Some(i) => {
+            let (name, ext) = filename.split_at(name_start + i);
+
+            format!("{}{}-{}{}", dir, name, n, ext)
+        }
+        None => format!("{}{}-{}", dir, filename, n),
+    }
+}
+
 pub(crate) async fn create_writer(
     operator: &Operator,
     headers: &HeaderMap,
     path: &str,
     output: Option<OutputOptions>,
+    segmentation: Option<Segmentation>,
+    token: Option<&str>,
 ) -> Result<Writer, anyhow::Error> {
     let mut writer_builder = operator.writer_with(path);
 
-    if let Some(output) = output
-        && output.set_content_type
-    {
+    // Upload segments as parts of a multipart upload with the same concurrency they are downloaded with
+    if let Some(segmentation) = segmentation {
+        writer_builder = writer_builder
+            .chunk(segmentation.size as usize)
+            .concurrent(segmentation.concurrency);
+    }
+
+    let Some(output) = output else {
+        return writer_builder
+            .await
+            .context("Failed to create storage writer");
+    };
+
+    if output.set_content_type {
         let content_type = output.content_type.or_else(|| {
             headers
                 .get("content-type")
@@ -137,31 +830,105 @@ pub(crate) async fn create_writer(
         }
     }
 
+    if let Some(content_disposition) = &output.content_disposition {
+        writer_builder = writer_builder.content_disposition(content_disposition);
+    }
+
+    if let Some(cache_control) = &output.cache_control {
+        writer_builder = writer_builder.cache_control(cache_control);
+    }
+
+    if let Some(content_encoding) = &output.content_encoding {
+        writer_builder = writer_builder.content_encoding(content_encoding);
+    } else if let Some(compress) = &output.compress {
+        writer_builder = writer_builder.content_encoding(compress.algorithm.content_encoding());
+    }
+
+    let mut user_metadata: Vec<(String, String)> = output.metadata.into_iter().collect();
+
+    user_metadata.extend(output.metadata_headers.iter().filter_map(|name| {
+        headers
+            .get(name.as_str())
+            .and_then(|v| v.to_str().ok())
+            .map(|v| (name.to_ascii_lowercase(), v.to_string()))
+    }));
+
+    // Remember the validators of the source, so the next conditional download can skip unchanged files
+    if output.only_if_changed && operator.info().full_capability().write_with_user_metadata {
+        let validators = [
+            (SOURCE_ETAG_METADATA, ETAG),
+            (SOURCE_LAST_MODIFIED_METADATA, LAST_MODIFIED),
+        ];
+
+        user_metadata.extend(validators.into_iter().filter_map(|(key, header)| {
+            headers
+                .get(header)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| (key.to_string(), v.to_string()))
+        }));
+    }
+
+    // Stamp the file, so a retried download step does not apply the policy to its own output
+    if let Some(token) = token
+        && !output.on_exists.is_overwrite()
+        && operator.info().full_capability().write_with_user_metadata
+    {
+        user_metadata.push((DOWNLOAD_TOKEN_METADATA.to_string(), token.to_string()));
+    }
+
+    if !user_metadata.is_empty() {
+        writer_builder = writer_builder.user_metadata(user_metadata);
+    }
+
     writer_builder
         .await
         .context("Failed to create storage writer")
 }
 
+/// Stream a file to storage and finalize the upload.
+///
+/// The upload is only finalized after the whole stream has been written and validated.
+/// On any failure (including cancellation) the writer is aborted instead, which discards multipart uploads,
+/// so no partially written object becomes visible at the target path.
+///
+/// The size, digests and checksum describe the downloaded payload before it is decompressed,
+/// while the maximum size limits the decompressed file.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn stream_file<S>(
-    mut stream: S,
+    stream: S,
     mut writer: Writer,
-) -> std::result::Result<u64, anyhow::Error>
+    checksum: Option<&Checksum>,
+    digests: &[DigestAlgorithm],
+    max_size: Option<u64>,
+    progress: Option<ProgressReporter>,
+    decompression: Option<Compression>,
+    encoder: Option<Encoder>,
+) -> Result<(u64, BTreeMap<DigestAlgorithm, String>), HandlerError>
 where
-    S: Stream<Item = std::result::Result<bytes::Bytes, reqwest::Error>> + Unpin,
+    S: Stream<Item = Result<bytes::Bytes>> + Send + Unpin,
 {
-    let mut size = 0u64;
-
-    // Stream data directly from HTTP response to storage
-    while let Some(chunk_result) = stream.next().await {
-        let chunk = chunk_result.with_context(|| "Failed to read chunk from HTTP response")?;
-
-        size += chunk.len() as u64;
+    let written = match write_stream(
+        stream,
+        &mut writer,
+        checksum,
+        digests,
+        max_size,
+        progress,
+        decompression,
+        encoder,
+    )
+    .await
+    {
+        Ok(written) => written,
+        Err(err) => {
+            // The original error is more relevant to the caller than a failed cleanup
+            if let Err(abort_err) = writer.abort().await {
+                tracing::warn!(error = %abort_err, "Failed to abort storage upload");
+            }
 
-        writer
-            .write(chunk)
-            .await
-            .context("Failed to write chunk to storage")?;
-    }
+            return Err(err);
+        }
+    };
 
     // Close the writer to finalize the upload
     writer
@@ -169,42 +936,1457 @@ where
         .await
         .context("Failed to finalize storage upload")?;
 
-    Ok(size)
+    Ok(written)
 }
 
-pub async fn process_download(
-    operator: &Operator,
-    response: reqwest::Response,
-    path: &str,
-    output: Option<OutputOptions>,
-) -> Result<u64, HandlerError> {
-    let writer = create_writer(operator, response.headers(), path, output).await?;
+#[allow(clippy::too_many_arguments)]
+async fn write_stream<S>(
+    stream: S,
+    writer: &mut Writer,
+    checksum: Option<&Checksum>,
+    digests: &[DigestAlgorithm],
+    max_size: Option<u64>,
+    mut progress: Option<ProgressReporter>,
+    decompression: Option<Compression>,
+    mut encoder: Option<Encoder>,
+) -> Result<(u64, BTreeMap<DigestAlgorithm, String>), HandlerError>
+where
+    S: Stream<Item = Result<bytes::Bytes>> + Send + Unpin,
+{
+    let mut size = 0u64;
+    let mut written = 0u64;
+    let mut digester = Digester::new(digests.iter().copied().chain(checksum.map(|c| c.algorithm)));
 
-    let stream = response.bytes_stream();
+    // Hash the payload as downloaded, so the checksum matches what the source publishes
+    let stream = stream
+        .inspect_ok(|chunk| {
+            size += chunk.len() as u64;
+            digester.update(chunk);
+        })
+        .boxed();
 
-    let size = stream_file(stream, writer)
-        .await
-        .context("Failed to stream file to storage")?;
+    let mut stream = match decompression {
+        Some(compression) => compression.decode(stream),
+        None => stream,
+    };
 
-    Ok(size)
-}
+    // Stream data directly from HTTP response to storage
+    while let Some(chunk_result) = stream.next().await {
+        let chunk = chunk_result.with_context(|| "Failed to read chunk from HTTP response")?;
 
-/// Convert an error to a terminal HandlerError
-pub fn terminal<E: std::fmt::Display>(e: E) -> HandlerError {
-    TerminalError::new(e.to_string()).into()
-}
+        written += chunk.len() as u64;
 
-pub fn http_error(e: reqwest::Error) -> HandlerError {
-    match e.status() {
-        Some(status) => {
-            let err = anyhow::Error::new(e)
-                .context(format!("HTTP request failed with status: {}", status));
-            if status.is_client_error() {
-                terminal(err)
+        if let Some(max_size) = max_size
+            && written > max_size
+        {
+            return Err(terminal(format!(
+                "Download exceeds the maximum size of {} bytes",
+                max_size
+            )));
+        }
+
+        let chunk = match encoder.as_mut() {
+            Some(encoder) => encoder.encode(&chunk).context("Failed to compress chunk")?,
+            None => chunk,
+        };
+
+        if !chunk.is_empty() {
+            writer
+                .write(chunk)
+                .await
+                .context("Failed to write chunk to storage")?;
+        }
+
+        if let Some(progress) = progress.as_mut()
+            && progress.report(written).await
+        {
+            return Err(cancelled());
+        }
+    }
+
+    drop(stream);
+
+    let digests = digester.finalize();
+
+    // Verify the checksum before the upload is finalized, so a mismatching file never becomes visible
+    verify_checksum(checksum, &digests)?;
+
+    if let Some(encoder) = encoder {
+        let chunk = encoder.finish().context("Failed to compress chunk")?;
+
+        writer
+            .write(chunk)
+            .await
+            .context("Failed to write chunk to storage")?;
+    }
+
+    Ok((size, digests))
+}
+
+pub(crate) fn verify_checksum(
+    checksum: Option<&Checksum>,
+    digests: &BTreeMap<DigestAlgorithm, String>,
+) -> Result<(), HandlerError> {
+    let Some(checksum) = checksum else {
+        return Ok(());
+    };
+
+    let digest = digests
+        .get(&checksum.algorithm)
+        .map(String::as_str)
+        .unwrap_or_default();
+
+    if !checksum.matches(digest) {
+        return Err(terminal(format!(
+            "Checksum mismatch: expected {} digest {}, got {}",
+            checksum.algorithm, checksum.value, digest
+        )));
+    }
+
+    Ok(())
+}
+
+/// Check applied to every path written to storage (the target, staging, sidecar and extracted paths)
+pub type CheckPath<'a> = &'a (dyn Fn(&str) -> Result<(), HandlerError> + Sync);
+
+#[allow(clippy::too_many_arguments)]
+pub async fn process_download(
+    client: &reqwest::Client,
+    ingress_client: &reqwest::Client,
+    operator: &Operator,
+    response: reqwest::Response,
+    path: &str,
+    options: DownloadOptions,
+    config: &Config,
+    check_path: CheckPath<'_>,
+) -> Result<StreamedFile, HandlerError> {
+    if path.is_empty() || path.ends_with('/') {
+        return Err(terminal(format!(
+            "Refusing to write to an empty key (path: {:?})",
+            path
+        )));
+    }
+
+    check_path(path)?;
+
+    let request = options.request.as_ref();
+
+    let max_resume_attempts = request
+        .and_then(|r| r.max_resume_attempts)
+        .unwrap_or(DEFAULT_MAX_RESUME_ATTEMPTS);
+
+    let segmentation = Segmentation::new(request, config);
+    let segmentation = segmentation.applies_to(&response).then_some(segmentation);
+
+    let max_size = request.and_then(|r| r.max_size).or(config.max_size);
+
+    if let Some(max_size) = max_size
+        && let Some(content_length) = response.content_length()
+        && content_length > max_size
+    {
+        return Err(terminal(format!(
+            "Download size of {} bytes exceeds the maximum size of {} bytes",
+            content_length, max_size
+        )));
+    }
+
+    let extract = options.output.as_ref().is_some_and(|o| o.extract);
+
+    let root = options.root.clone().unwrap_or_default();
+
+    let staging_prefix = options.output.as_ref().filter(|o| o.staged).map(|o| {
+        o.staging_prefix
+            .clone()
+            .or_else(|| config.staging_prefix.clone())
+            .unwrap_or_else(|| DEFAULT_STAGING_PREFIX.to_string())
+    });
+
+    let staging_path = staging_prefix
+        .as_deref()
+        .map(|prefix| staging_path(&root, prefix, path))
+        .transpose()?;
+
+    // Extracted entries are staged individually
+    if let Some(staging_path) = staging_path.as_deref().filter(|_| !extract) {
+        check_path(staging_path)?;
+    }
+
+    if staging_path.is_some() {
+        let capability = operator.info().full_capability();
+
+        if !capability.rename && !capability.copy {
+            return Err(terminal(
+                "Staged downloads require a storage that supports rename or copy",
+            ));
+        }
+    }
+
+    if let Some(output) = &options.output {
+        check_write_capability(operator, output)?;
+    }
+
+    if extract
+        && options.output.as_ref().is_some_and(|o| {
+            o.only_if_changed || !o.on_exists.is_overwrite() || o.compress.is_some()
+        })
+    {
+        return Err(terminal(
+            "Archive extraction cannot be combined with onlyIfChanged, onExists or compress",
+        ));
+    }
+
+    let encodings = content_encodings(response.headers())?;
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .map(String::from);
+
+    // The target path may be chosen by the caller, the filename of the source describes the payload
+    let source_filename = filename_from_response(&response, &config.filename).unwrap_or_default();
+
+    let decompression = options
+        .output
+        .as_ref()
+        .filter(|o| o.decompress && !extract)
+        .and_then(|_| Compression::detect(Some(&source_filename), content_type.as_deref()));
+
+    // The content length does not match the size of the stored file when the payload is decoded
+    let total_bytes = response
+        .content_length()
+        .filter(|_| encodings.is_empty() && decompression.is_none());
+
+    let progress = options
+        .id
+        .as_deref()
+        .zip(config.ingress_url.as_ref())
+        .map(|(id, ingress_url)| {
+            ProgressReporter::new(
+                ingress_client.clone(),
+                ingress_url,
+                id,
+                config
+                    .progress_interval
+                    .unwrap_or(DEFAULT_PROGRESS_INTERVAL),
+                total_bytes,
+            )
+        })
+        .transpose()
+        .map_err(terminal)?;
+
+    let sidecar = options
+        .output
+        .as_ref()
+        .filter(|o| o.sidecar)
+        .map(|_| Sidecar::new(options.id.clone(), options.url.clone(), &response));
+
+    if sidecar.is_some() {
+        check_path(&format!("{}{}", path, SIDECAR_SUFFIX))?;
+    }
+
+    if extract {
+        let stream = download_stream(
+            client,
+            response,
+            options.request,
+            segmentation,
+            max_resume_attempts,
+        );
+        let stream = encodings.into_iter().fold(stream, |s, c| c.decode(s));
+
+        let (format, stream) =
+            detect_archive(stream, &source_filename, content_type.as_deref()).await?;
+
+        let mut extractor = Extractor::new(operator, extraction_prefix(path), max_size, check_path);
+
+        if let Some(prefix) = staging_prefix.as_deref() {
+            extractor = extractor.with_staging(&root, prefix);
+        }
+
+        let file = extract_file(
+            stream,
+            extractor,
+            format,
+            options.checksum.as_ref(),
+            &options.digests,
+            progress,
+        )
+        .await?;
+
+        if let Some(sidecar) = sidecar {
+            sidecar.write(operator, path, &file).await?;
+        }
+
+        return Ok(file);
+    }
+
+    // The content type of the source describes the compressed payload, not the decompressed file
+    let mut headers = response.headers().clone();
+
+    if decompression.is_some() {
+        headers.remove(CONTENT_TYPE);
+    }
+
+    let encoder = options
+        .output
+        .as_ref()
+        .and_then(|o| o.compress.as_ref())
+        .map(Encoder::new)
+        .transpose()?;
+
+    let writer = create_writer(
+        operator,
+        &headers,
+        staging_path.as_deref().unwrap_or(path),
+        options.output,
+        segmentation,
+        options.token.as_deref(),
+    )
+    .await?;
+
+    let stream = download_stream(
+        client,
+        response,
+        options.request,
+        segmentation,
+        max_resume_attempts,
+    );
+    let stream = encodings.into_iter().fold(stream, |s, c| c.decode(s));
+
+    let (size, digests) = stream_file(
+        stream,
+        writer,
+        options.checksum.as_ref(),
+        &options.digests,
+        max_size,
+        progress,
+        decompression,
+        encoder,
+    )
+    .await?;
+
+    if let Some(staging_path) = staging_path {
+        publish(operator, &staging_path, path).await?;
+    }
+
+    let file = StreamedFile {
+        path: path.to_string(),
+        size,
+        digests,
+        entries: Vec::new(),
+    };
+
+    if let Some(sidecar) = sidecar {
+        sidecar.write(operator, path, &file).await?;
+    }
+
+    Ok(file)
+}
+
+/// Check that the storage supports the metadata requested for the stored file
+fn check_write_capability(operator: &Operator, output: &OutputOptions) -> Result<(), HandlerError> {
+    let capability = operator.info().full_capability();
+
+    let unsupported = [
+        (
+            "metadata",
+            (!output.metadata.is_empty() || !output.metadata_headers.is_empty())
+                && !capability.write_with_user_metadata,
+        ),
+        (
+            "contentDisposition",
+            output.content_disposition.is_some() && !capability.write_with_content_disposition,
+        ),
+        (
+            "cacheControl",
+            output.cache_control.is_some() && !capability.write_with_cache_control,
+        ),
+        (
+            "contentEncoding",
+            output.content_encoding.is_some() && !capability.write_with_content_encoding,
+        ),
+        (
+            "compress",
+            output.compress.is_some() && !capability.write_with_content_encoding,
+        ),
+    ];
+
+    if let Some((option, _)) = unsupported
+        .into_iter()
+        .find(|(_, unsupported)| *unsupported)
+    {
+        return Err(terminal(format!(
+            "The storage does not support the {} output option",
+            option
+        )));
+    }
+
+    Ok(())
+}
+
+fn download_stream(
+    client: &reqwest::Client,
+    response: Response,
+    options: Option<RequestOptions>,
+    segmentation: Option<Segmentation>,
+    max_resume_attempts: u32,
+) -> BoxStream<'static, Result<bytes::Bytes>> {
+    match segmentation {
+        Some(segmentation) => segmented_stream(
+            client.clone(),
+            &response,
+            options,
+            segmentation,
+            max_resume_attempts,
+        ),
+        None => resumable_stream(client.clone(), response, options, max_resume_attempts),
+    }
+}
+
+/// Directory of the target path archives are extracted to (eg. "dir/file.zip" -> "dir/")
+fn extraction_prefix(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(i) => &path[..=i],
+        None => "",
+    }
+}
+
+/// Default prefix of the staging path for staged downloads
+pub(crate) const DEFAULT_STAGING_PREFIX: &str = ".incoming/";
+
+/// Nest the target path under the staging prefix inside the root directory
+/// (eg. "downloads/acme/dir/file.pdf" -> "downloads/acme/.incoming/dir/file.pdf"),
+/// so staged files are confined to the same directory as the final path.
+pub(crate) fn staging_path(root: &str, prefix: &str, path: &str) -> Result<String, HandlerError> {
+    let invalid = prefix.starts_with(['/', '\\'])
+        || prefix
+            .trim_end_matches('/')
+            .split(['/', '\\'])
+            .any(|segment| matches!(segment, "" | "." | ".."));
+
+    if invalid {
+        return Err(terminal(format!("Invalid staging prefix: {:?}", prefix)));
+    }
+
+    let relative = path.strip_prefix(root).unwrap_or(path);
+
+    Ok(format!(
+        "{}{}/{}",
+        root,
+        prefix.trim_end_matches('/'),
+        relative.trim_start_matches('/')
+    ))
+}
+
+/// Move a fully written file from the staging path to its final path
+pub(crate) async fn publish(operator: &Operator, staging_path: &str, path: &str) -> Result<()> {
+    if operator.info().full_capability().rename {
+        return operator
+            .rename(staging_path, path)
+            .await
+            .context("Failed to move staged file to its final path");
+    }
+
+    operator
+        .copy(staging_path, path)
+        .await
+        .context("Failed to copy staged file to its final path")?;
+
+    if let Err(err) = operator.delete(staging_path).await {
+        tracing::warn!(error = %err, path = staging_path, "Failed to delete staged file");
+    }
+
+    Ok(())
+}
+
+/// Convert an error to a terminal HandlerError
+pub fn terminal<E: std::fmt::Display>(e: E) -> HandlerError {
+    TerminalError::new(e.to_string()).into()
+}
+
+pub fn http_error(e: reqwest::Error) -> HandlerError {
+    match e.status() {
+        Some(status) => {
+            let err = anyhow::Error::new(e)
+                .context(format!("HTTP request failed with status: {}", status));
+            if status.is_client_error() {
+                terminal(err)
             } else {
                 err.into()
             }
         }
-        None => e.into(),
+        None => match egress_error(&e) {
+            // Requests rejected by the egress policy would be rejected again on retry
+            Some(err) => terminal(err),
+            None => e.into(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        progress::CANCELLED_CODE,
+        testing::{self, terminal_message},
+    };
+
+    fn output(only_if_changed: bool, on_exists: OnExists) -> OutputOptions {
+        OutputOptions {
+            only_if_changed,
+            on_exists,
+            ..Default::default()
+        }
+    }
+
+    fn http_response(status: StatusCode) -> Response {
+        http::Response::builder()
+            .status(status)
+            .body("")
+            .unwrap()
+            .into()
+    }
+
+    /// Stream chunks to a file in storage, returning the number of bytes written
+    async fn stream(
+        operator: &Operator,
+        path: &str,
+        chunks: Vec<Result<bytes::Bytes>>,
+        checksum: Option<&Checksum>,
+        max_size: Option<u64>,
+    ) -> Result<u64, HandlerError> {
+        let writer = operator.writer(path).await.unwrap();
+
+        stream_file(
+            futures::stream::iter(chunks),
+            writer,
+            checksum,
+            &[],
+            max_size,
+            None,
+            None,
+            None,
+        )
+        .await
+        .map(|(size, _)| size)
+    }
+
+    /// Write a previously downloaded file, storing the validators of the response it came from
+    async fn write_file(operator: &Operator, path: &str) {
+        let headers = HeaderMap::from_iter([
+            (ETAG, HeaderValue::from_static("\"v1\"")),
+            (
+                LAST_MODIFIED,
+                HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+            ),
+        ]);
+
+        let mut writer = create_writer(
+            operator,
+            &headers,
+            path,
+            Some(output(true, OnExists::Overwrite)),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        writer.write("content").await.unwrap();
+        writer.close().await.unwrap();
+    }
+
+    /// Path and size of a resolved promise or code and message of a rejected one
+    type Completion = Result<(String, u64), (u16, String)>;
+
+    /// Records how the completion promise of a workflow was completed
+    #[derive(Default)]
+    struct Promise(std::sync::Mutex<Vec<Completion>>);
+
+    impl CompletionPromise for Promise {
+        fn resolve(&self, response: Json<DownloadResponse>) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(Ok((response.0.path, response.0.size)));
+        }
+
+        fn reject(&self, error: TerminalError) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(Err((error.code(), error.message().to_string())));
+        }
+    }
+
+    /// Test that the completion promise is resolved with the response or rejected with the error of the download
+    #[test]
+    fn test_complete_workflow() {
+        let response = DownloadResponse {
+            path: "file.pdf".to_string(),
+            size: 7,
+            digests: BTreeMap::new(),
+            unchanged: false,
+            skipped: false,
+            entries: Vec::new(),
+        };
+
+        let promise = Promise::default();
+        complete_workflow(&promise, &Ok(Json(response)));
+
+        assert_eq!(
+            promise.0.into_inner().unwrap(),
+            vec![Ok(("file.pdf".to_string(), 7))]
+        );
+
+        let test_cases = vec![
+            (
+                TerminalError::new("A file already exists at the target path: file.pdf"),
+                (500, "A file already exists at the target path: file.pdf"),
+            ),
+            (
+                TerminalError::new_with_code(CANCELLED_CODE, "Download cancelled"),
+                (CANCELLED_CODE, "Download cancelled"),
+            ),
+        ];
+
+        for (error, (code, message)) in test_cases {
+            let promise = Promise::default();
+            complete_workflow(&promise, &Err(error));
+
+            assert_eq!(
+                promise.0.into_inner().unwrap(),
+                vec![Err((code, message.to_string()))]
+            );
+        }
+    }
+
+    /// Test that batch results keep the order of the requests and report failures per item
+    #[test]
+    fn test_run_batch() {
+        let downloads = vec!["a", "fail", "b", "c"];
+
+        let results = futures::executor::block_on(run_batch(downloads, 2, |path| async move {
+            if path == "fail" {
+                return Err(TerminalError::new("boom"));
+            }
+
+            Ok(Json(DownloadResponse {
+                path: path.to_string(),
+                size: 0,
+                digests: BTreeMap::new(),
+                unchanged: false,
+                skipped: false,
+                entries: Vec::new(),
+            }))
+        }));
+
+        let summary: Vec<String> = results
+            .into_iter()
+            .map(|result| match result {
+                DownloadResult::Succeeded(response) => response.path,
+                DownloadResult::Failed { error } => format!("error: {}", error),
+            })
+            .collect();
+
+        assert_eq!(summary, vec!["a", "error: boom", "b", "c"]);
+    }
+
+    /// Test that huge concurrency values are capped instead of allocating a slot per requested download
+    #[test]
+    fn test_run_batch_concurrency() {
+        let results =
+            futures::executor::block_on(run_batch(vec!["a", "b"], usize::MAX, |path| async move {
+                Ok(Json(DownloadResponse {
+                    path: path.to_string(),
+                    size: 0,
+                    digests: BTreeMap::new(),
+                    unchanged: false,
+                    skipped: false,
+                    entries: Vec::new(),
+                }))
+            }));
+
+        assert_eq!(results.len(), 2);
+
+        let config = Config {
+            batch_concurrency: Some(8),
+            max_batch_concurrency: Some(16),
+            ..Default::default()
+        };
+
+        let test_cases = vec![
+            (None, 8),
+            (Some(2), 2),
+            (Some(1_000_000_000_000), 16),
+            (Some(usize::MAX), 16),
+        ];
+
+        for (requested, expected) in test_cases {
+            assert_eq!(
+                batch_concurrency(requested, &config),
+                expected,
+                "Failed for requested concurrency {:?}",
+                requested
+            );
+        }
+
+        assert_eq!(
+            batch_concurrency(Some(usize::MAX), &Config::default()),
+            DEFAULT_MAX_BATCH_CONCURRENCY
+        );
+    }
+
+    /// Test that numeric suffixes are inserted before the extension of the filename
+    #[test]
+    fn test_with_numeric_suffix() {
+        let test_cases = vec![
+            ("file.pdf", 1, "file-1.pdf"),
+            ("dir/file.tar.gz", 2, "dir/file-2.tar.gz"),
+            ("dir.d/file", 3, "dir.d/file-3"),
+            (".hidden", 1, ".hidden-1"),
+            (".hidden.txt", 1, ".hidden-1.txt"),
+        ];
+
+        for (path, n, expected) in test_cases {
+            assert_eq!(
+                with_numeric_suffix(path, n),
+                expected,
+                "Failed for path '{}'",
+                path
+            );
+        }
+    }
+
+    /// Test that the staging path is the final path nested under the staging prefix inside the root directory
+    #[test]
+    fn test_staging_path() {
+        let test_cases = vec![
+            ("", ".incoming/", "file.pdf", ".incoming/file.pdf"),
+            (
+                "",
+                ".incoming",
+                "downloads/file.pdf",
+                ".incoming/downloads/file.pdf",
+            ),
+            (
+                "",
+                ".incoming/",
+                "/absolute/file.pdf",
+                ".incoming/absolute/file.pdf",
+            ),
+            ("", "tmp/staging/", "file.pdf", "tmp/staging/file.pdf"),
+            (
+                "downloads/acme/",
+                ".incoming/",
+                "downloads/acme/dir/file.pdf",
+                "downloads/acme/.incoming/dir/file.pdf",
+            ),
+        ];
+
+        for (root, prefix, path, expected) in test_cases {
+            assert_eq!(
+                staging_path(root, prefix, path).unwrap(),
+                expected,
+                "Failed for root '{}', prefix '{}' and path '{}'",
+                root,
+                prefix,
+                path
+            );
+        }
+
+        for prefix in ["", "/", "/tmp/", "../", "a/../../", "a//b/", "./", "..\\"] {
+            assert!(
+                staging_path("downloads/acme/", prefix, "downloads/acme/file.pdf").is_err(),
+                "Should fail for prefix '{}'",
+                prefix
+            );
+        }
+    }
+
+    /// Test that the validators of the response are stored in the user metadata and sent as conditional headers
+    #[test]
+    fn test_precheck() {
+        let operator = testing::operator();
+
+        futures::executor::block_on(async {
+            write_file(&operator, "file.pdf").await;
+
+            let metadata = operator.stat("file.pdf").await.unwrap();
+            let user_metadata = metadata.user_metadata().unwrap();
+
+            assert_eq!(user_metadata[SOURCE_ETAG_METADATA], "\"v1\"");
+            assert_eq!(
+                user_metadata[SOURCE_LAST_MODIFIED_METADATA],
+                "Wed, 21 Oct 2015 07:28:00 GMT"
+            );
+
+            let only_if_changed = output(true, OnExists::Overwrite);
+
+            let Precheck::Send(headers) =
+                precheck(&operator, Some("file.pdf"), &only_if_changed, None)
+                    .await
+                    .unwrap()
+            else {
+                panic!("Should send the request for a changed file");
+            };
+
+            assert_eq!(headers[IF_NONE_MATCH], "\"v1\"");
+            assert_eq!(headers[IF_MODIFIED_SINCE], "Wed, 21 Oct 2015 07:28:00 GMT");
+
+            let Precheck::Send(headers) =
+                precheck(&operator, Some("missing.pdf"), &only_if_changed, None)
+                    .await
+                    .unwrap()
+            else {
+                panic!("Should send the request for a missing file");
+            };
+
+            assert!(headers.is_empty());
+
+            let Precheck::Skip(response) = precheck(
+                &operator,
+                Some("file.pdf"),
+                &output(false, OnExists::Skip),
+                None,
+            )
+            .await
+            .unwrap() else {
+                panic!("Should skip an existing file");
+            };
+
+            assert!(response.skipped);
+            assert_eq!(response.size, 7);
+
+            assert!(
+                precheck(
+                    &operator,
+                    Some("file.pdf"),
+                    &output(false, OnExists::Fail),
+                    None
+                )
+                .await
+                .is_err()
+            );
+
+            // The target of an output naming a directory is only known once the response is available
+            assert!(
+                precheck(&operator, None, &only_if_changed, None)
+                    .await
+                    .is_err()
+            );
+            assert!(matches!(
+                precheck(&operator, None, &output(false, OnExists::Fail), None)
+                    .await
+                    .unwrap(),
+                Precheck::Send(headers) if headers.is_empty()
+            ));
+
+            // A file written by a previous attempt of the download is downloaded again
+            let mut writer = create_writer(
+                &operator,
+                &HeaderMap::new(),
+                "retried.pdf",
+                Some(output(false, OnExists::Skip)),
+                None,
+                Some("token"),
+            )
+            .await
+            .unwrap();
+
+            writer.write("content").await.unwrap();
+            writer.close().await.unwrap();
+
+            for on_exists in [OnExists::Skip, OnExists::Fail] {
+                assert!(matches!(
+                    precheck(&operator, Some("retried.pdf"), &output(false, on_exists), Some("token"))
+                        .await
+                        .unwrap(),
+                    Precheck::Send(headers) if headers.is_empty()
+                ));
+            }
+        });
+    }
+
+    /// Test that a not modified response keeps the existing file and other responses apply the on exists policy
+    #[test]
+    fn test_check_target() {
+        let operator = testing::operator();
+
+        futures::executor::block_on(async {
+            write_file(&operator, "file.pdf").await;
+
+            let Target::Skip(response) = check_target(
+                &operator,
+                &http_response(StatusCode::NOT_MODIFIED),
+                "file.pdf".to_string(),
+                OnExists::Overwrite,
+                None,
+            )
+            .await
+            .unwrap() else {
+                panic!("Should keep the unchanged file");
+            };
+
+            assert!(response.unchanged);
+            assert_eq!(response.path, "file.pdf");
+            assert_eq!(response.size, 7);
+
+            assert!(
+                check_target(
+                    &operator,
+                    &http_response(StatusCode::NOT_MODIFIED),
+                    "missing.pdf".to_string(),
+                    OnExists::Overwrite,
+                    None,
+                )
+                .await
+                .is_err()
+            );
+
+            let test_cases = vec![
+                ("file.pdf", OnExists::Overwrite, Some("file.pdf")),
+                ("file.pdf", OnExists::Rename, Some("file-1.pdf")),
+                ("file.pdf", OnExists::Skip, None),
+                ("missing.pdf", OnExists::Skip, Some("missing.pdf")),
+                ("missing.pdf", OnExists::Fail, Some("missing.pdf")),
+            ];
+
+            for (path, on_exists, expected) in test_cases {
+                let target = check_target(
+                    &operator,
+                    &http_response(StatusCode::OK),
+                    path.to_string(),
+                    on_exists,
+                    None,
+                )
+                .await
+                .unwrap();
+
+                match (target, expected) {
+                    (Target::Write(path), Some(expected)) => assert_eq!(path, expected),
+                    (Target::Skip(response), None) => assert!(response.skipped),
+                    _ => panic!("Unexpected target for path '{}' and {:?}", path, on_exists),
+                }
+            }
+
+            assert!(
+                check_target(
+                    &operator,
+                    &http_response(StatusCode::OK),
+                    "file.pdf".to_string(),
+                    OnExists::Fail,
+                    None,
+                )
+                .await
+                .is_err()
+            );
+        });
+    }
+
+    /// Test that a file written by a previous attempt of the download step is treated as its own output
+    #[test]
+    fn test_check_target_retry() {
+        let operator = testing::operator();
+
+        futures::executor::block_on(async {
+            let mut writer = create_writer(
+                &operator,
+                &HeaderMap::new(),
+                "file.pdf",
+                Some(output(false, OnExists::Fail)),
+                None,
+                Some("token"),
+            )
+            .await
+            .unwrap();
+
+            writer.write("content").await.unwrap();
+            writer.close().await.unwrap();
+
+            let test_cases = vec![
+                (OnExists::Fail, Some("token"), Some("file.pdf")),
+                (OnExists::Skip, Some("token"), Some("file.pdf")),
+                (OnExists::Rename, Some("token"), Some("file.pdf")),
+                (OnExists::Fail, Some("other"), None),
+                (OnExists::Fail, None, None),
+            ];
+
+            for (on_exists, token, expected) in test_cases {
+                let target = check_target(
+                    &operator,
+                    &http_response(StatusCode::OK),
+                    "file.pdf".to_string(),
+                    on_exists,
+                    token,
+                )
+                .await;
+
+                match (target, expected) {
+                    (Ok(Target::Write(path)), Some(expected)) => assert_eq!(path, expected),
+                    (Err(_), None) => {}
+                    _ => panic!(
+                        "Unexpected target for {:?} and token {:?}",
+                        on_exists, token
+                    ),
+                }
+            }
+
+            // The previous attempt renamed the file, so the retry writes to the same free path
+            write_file(&operator, "report.pdf").await;
+
+            let mut writer = create_writer(
+                &operator,
+                &HeaderMap::new(),
+                "report-1.pdf",
+                Some(output(false, OnExists::Rename)),
+                None,
+                Some("token"),
+            )
+            .await
+            .unwrap();
+
+            writer.write("content").await.unwrap();
+            writer.close().await.unwrap();
+
+            for (token, expected) in [(Some("token"), "report-1.pdf"), (None, "report-2.pdf")] {
+                let Target::Write(path) = check_target(
+                    &operator,
+                    &http_response(StatusCode::OK),
+                    "report.pdf".to_string(),
+                    OnExists::Rename,
+                    token,
+                )
+                .await
+                .unwrap() else {
+                    panic!("Should rename the file");
+                };
+
+                assert_eq!(path, expected);
+            }
+        });
+    }
+
+    /// Test that a download exceeding the maximum size fails terminally and leaves no file behind
+    #[test]
+    fn test_stream_file_max_size() {
+        let operator = testing::operator();
+        let chunks = || vec![Ok("abcd".into()), Ok("efgh".into())];
+
+        futures::executor::block_on(async {
+            assert_eq!(
+                stream(&operator, "fits.bin", chunks(), None, Some(8))
+                    .await
+                    .unwrap(),
+                8
+            );
+            assert_eq!(
+                operator.read("fits.bin").await.unwrap().to_vec(),
+                b"abcdefgh"
+            );
+
+            let err = stream(&operator, "exceeds.bin", chunks(), None, Some(7))
+                .await
+                .unwrap_err();
+
+            assert_eq!(
+                terminal_message(&err).as_deref(),
+                Some("Download exceeds the maximum size of 7 bytes")
+            );
+            assert!(!operator.exists("exceeds.bin").await.unwrap());
+        });
+    }
+
+    /// Test that a failing chunk aborts the upload, so no partial file is left behind and the download is retried
+    #[test]
+    fn test_stream_file_chunk_error() {
+        let operator = testing::operator();
+
+        futures::executor::block_on(async {
+            let chunks = vec![
+                Ok("abcd".into()),
+                Err(anyhow::anyhow!("connection reset")),
+                Ok("efgh".into()),
+            ];
+
+            let err = stream(&operator, "partial.bin", chunks, None, None)
+                .await
+                .unwrap_err();
+
+            assert_eq!(terminal_message(&err), None);
+            assert!(!operator.exists("partial.bin").await.unwrap());
+        });
+    }
+
+    /// Test that a file not matching the expected checksum is never finalized
+    #[test]
+    fn test_stream_file_checksum() {
+        let operator = testing::operator();
+        let checksum = |value: &str| Checksum {
+            algorithm: DigestAlgorithm::Sha256,
+            value: value.to_string(),
+        };
+
+        futures::executor::block_on(async {
+            let matching =
+                checksum("88D4266FD4E6338D13B845FCF289579D209C897823B9217DA3E161936F031589");
+
+            stream(
+                &operator,
+                "matching.bin",
+                vec![Ok("ab".into()), Ok("cd".into())],
+                Some(&matching),
+                None,
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(
+                operator.read("matching.bin").await.unwrap().to_vec(),
+                b"abcd"
+            );
+
+            let mismatching = checksum(&"0".repeat(64));
+
+            let err = stream(
+                &operator,
+                "mismatching.bin",
+                vec![Ok("ab".into()), Ok("cd".into())],
+                Some(&mismatching),
+                None,
+            )
+            .await
+            .unwrap_err();
+
+            assert!(
+                terminal_message(&err)
+                    .is_some_and(|message| message.starts_with("Checksum mismatch"))
+            );
+            assert!(!operator.exists("mismatching.bin").await.unwrap());
+        });
+    }
+
+    /// Test that the output options are passed through to the stored file
+    #[test]
+    fn test_create_writer_passthrough() {
+        let operator = testing::operator();
+        let headers = HeaderMap::from_iter([
+            (ETAG, HeaderValue::from_static("\"v1\"")),
+            (CONTENT_TYPE, HeaderValue::from_static("text/csv")),
+        ]);
+
+        let output = OutputOptions {
+            set_content_type: true,
+            content_disposition: Some("attachment; filename=report.csv".to_string()),
+            cache_control: Some("max-age=60".to_string()),
+            content_encoding: Some("identity".to_string()),
+            metadata: HashMap::from([("job".to_string(), "42".to_string())]),
+            metadata_headers: vec!["ETag".to_string(), "x-missing".to_string()],
+            ..Default::default()
+        };
+
+        futures::executor::block_on(async {
+            check_write_capability(&operator, &output).unwrap();
+
+            let mut writer =
+                create_writer(&operator, &headers, "report.csv", Some(output), None, None)
+                    .await
+                    .unwrap();
+
+            writer.write("a,b").await.unwrap();
+            writer.close().await.unwrap();
+
+            let metadata = operator.stat("report.csv").await.unwrap();
+
+            assert_eq!(metadata.content_type(), Some("text/csv"));
+            assert_eq!(
+                metadata.content_disposition(),
+                Some("attachment; filename=report.csv")
+            );
+            assert_eq!(metadata.cache_control(), Some("max-age=60"));
+            assert_eq!(metadata.content_encoding(), Some("identity"));
+            assert_eq!(
+                metadata.user_metadata(),
+                Some(&HashMap::from([
+                    ("job".to_string(), "42".to_string()),
+                    ("etag".to_string(), "\"v1\"".to_string()),
+                ]))
+            );
+
+            let compress = OutputOptions {
+                compress: Some(CompressOptions {
+                    algorithm: CompressionAlgorithm::Gzip,
+                    level: None,
+                }),
+                ..Default::default()
+            };
+
+            let mut writer = create_writer(
+                &operator,
+                &headers,
+                "report.csv.gz",
+                Some(compress),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+            writer.write("compressed").await.unwrap();
+            writer.close().await.unwrap();
+
+            assert_eq!(
+                operator
+                    .stat("report.csv.gz")
+                    .await
+                    .unwrap()
+                    .content_encoding(),
+                Some("gzip")
+            );
+        });
+    }
+
+    /// Test that output options the storage cannot apply fail the download
+    #[test]
+    fn test_check_write_capability() {
+        let operator = testing::operator();
+
+        operator
+            .inner()
+            .info_dyn()
+            .update_full_capability(|mut capability| {
+                capability.write_with_content_disposition = false;
+                capability.write_with_cache_control = false;
+                capability.write_with_content_encoding = false;
+                capability
+            });
+
+        let compress = Some(CompressOptions {
+            algorithm: CompressionAlgorithm::Zstd,
+            level: None,
+        });
+
+        let test_cases = vec![
+            (
+                OutputOptions {
+                    content_disposition: Some("inline".to_string()),
+                    ..Default::default()
+                },
+                "contentDisposition",
+            ),
+            (
+                OutputOptions {
+                    cache_control: Some("no-cache".to_string()),
+                    ..Default::default()
+                },
+                "cacheControl",
+            ),
+            (
+                OutputOptions {
+                    content_encoding: Some("gzip".to_string()),
+                    ..Default::default()
+                },
+                "contentEncoding",
+            ),
+            (
+                OutputOptions {
+                    compress,
+                    ..Default::default()
+                },
+                "compress",
+            ),
+        ];
+
+        for (output, option) in test_cases {
+            let err = check_write_capability(&operator, &output).unwrap_err();
+
+            assert_eq!(
+                terminal_message(&err),
+                Some(format!(
+                    "The storage does not support the {} output option",
+                    option
+                ))
+            );
+        }
+
+        let metadata = OutputOptions {
+            metadata_headers: vec!["etag".to_string()],
+            ..Default::default()
+        };
+
+        // Without the user metadata support of the test layer
+        let memory = Operator::new(opendal::services::Memory::default())
+            .unwrap()
+            .finish();
+
+        assert!(check_write_capability(&operator, &metadata).is_ok());
+        assert_eq!(
+            terminal_message(&check_write_capability(&memory, &metadata).unwrap_err()).as_deref(),
+            Some("The storage does not support the metadata output option")
+        );
+    }
+
+    /// Test that the archive format is detected from the filename of the source rather than the target path
+    #[test]
+    fn test_process_download_extract() {
+        use reqwest::ResponseBuilderExt as _;
+        use std::io::Write as _;
+
+        let operator = testing::operator();
+
+        let tar = futures::executor::block_on(async {
+            let mut builder = tokio_tar::Builder::new_non_terminated(Vec::new());
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(3);
+            header.set_mode(0o644);
+            header.set_cksum();
+
+            builder
+                .append_data(&mut header, "a.csv", &b"a,b"[..])
+                .await
+                .unwrap();
+            builder.into_inner().await.unwrap()
+        });
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&tar).unwrap();
+
+        // The content type of the server is wrong, the filename from Content-Disposition is not
+        let response: Response = http::Response::builder()
+            .url(Url::parse("https://api/export?id=1").unwrap())
+            .header("content-disposition", "attachment; filename=data.tar.gz")
+            .header(CONTENT_TYPE, "application/zip")
+            .body(encoder.finish().unwrap())
+            .unwrap()
+            .into();
+
+        let file = futures::executor::block_on(process_download(
+            &reqwest::Client::new(),
+            &reqwest::Client::new(),
+            &operator,
+            response,
+            "exports/export",
+            DownloadOptions {
+                output: Some(OutputOptions {
+                    extract: true,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            &Config::default(),
+            &|_| Ok(()),
+        ))
+        .unwrap();
+
+        assert_eq!(file.entries.len(), 1);
+        assert_eq!(
+            futures::executor::block_on(operator.read("exports/a.csv"))
+                .unwrap()
+                .to_vec(),
+            b"a,b"
+        );
+    }
+
+    /// Test that a cancellation requested through the progress object aborts the upload
+    #[tokio::test]
+    async fn test_stream_file_cancelled() {
+        let operator = testing::operator();
+        let ingress_url =
+            testing::serve(|_| testing::Reply::new(200, br#"{"cancelled":true}"#)).await;
+
+        let progress = ProgressReporter::new(
+            reqwest::Client::new(),
+            &ingress_url,
+            "abc",
+            Duration::ZERO,
+            None,
+        )
+        .unwrap();
+
+        let err = stream_file(
+            futures::stream::iter(vec![Ok("ab".into()), Ok("cd".into())]),
+            operator.writer("cancelled.bin").await.unwrap(),
+            None,
+            &[],
+            None,
+            Some(progress),
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            testing::terminal_error(&err),
+            Some((
+                crate::progress::CANCELLED_CODE,
+                "Download cancelled".to_string()
+            ))
+        );
+        assert!(!operator.exists("cancelled.bin").await.unwrap());
+    }
+
+    /// Test that the checksum of a decompressed file is verified against the downloaded payload
+    #[test]
+    fn test_stream_file_decompress_checksum() {
+        use sha2::{Digest as _, Sha256};
+        use std::io::Write as _;
+
+        let operator = testing::operator();
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"abcd").unwrap();
+        let payload = encoder.finish().unwrap();
+
+        let checksum = |value: String| Checksum {
+            algorithm: DigestAlgorithm::Sha256,
+            value,
+        };
+
+        let decompress = |path: &'static str, checksum: Checksum| {
+            let operator = operator.clone();
+            let payload = payload.clone();
+
+            async move {
+                let writer = operator.writer(path).await.unwrap();
+
+                stream_file(
+                    futures::stream::iter(vec![Ok(payload.into())]),
+                    writer,
+                    Some(&checksum),
+                    &[DigestAlgorithm::Sha256],
+                    None,
+                    None,
+                    Some(Compression::Gzip),
+                    None,
+                )
+                .await
+            }
+        };
+
+        futures::executor::block_on(async {
+            let compressed = hex::encode(Sha256::digest(&payload));
+
+            let (size, digests) = decompress("data.bin", checksum(compressed.clone()))
+                .await
+                .unwrap();
+
+            assert_eq!(size, payload.len() as u64);
+            assert_eq!(digests[&DigestAlgorithm::Sha256], compressed);
+            assert_eq!(operator.read("data.bin").await.unwrap().to_vec(), b"abcd");
+
+            let decompressed = hex::encode(Sha256::digest(b"abcd"));
+
+            let err = decompress("mismatching.bin", checksum(decompressed))
+                .await
+                .unwrap_err();
+
+            assert!(
+                terminal_message(&err)
+                    .is_some_and(|message| message.starts_with("Checksum mismatch"))
+            );
+            assert!(!operator.exists("mismatching.bin").await.unwrap());
+        });
     }
 }
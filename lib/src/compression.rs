@@ -0,0 +1,368 @@
+use std::{
+    io::{self, Write as _},
+    pin::Pin,
+};
+
+use anyhow::{Context as _, Result};
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BzDecoder, GzipDecoder, XzDecoder, ZlibDecoder, ZstdDecoder,
+};
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use futures::{StreamExt as _, stream::BoxStream};
+use reqwest::header::{CONTENT_ENCODING, HeaderMap};
+use restate_sdk::errors::HandlerError;
+use tokio::io::AsyncRead;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::common::{CompressOptions, CompressionAlgorithm, terminal};
+
+/// Compression formats of downloaded payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    /// Detect the compression of a single-file payload from its filename or content type
+    pub(crate) fn detect(filename: Option<&str>, content_type: Option<&str>) -> Option<Self> {
+        filename
+            .and_then(Self::from_filename)
+            .or_else(|| content_type.and_then(Self::from_content_type))
+    }
+
+    fn from_filename(filename: &str) -> Option<Self> {
+        let (_, extension) = filename.rsplit_once('.')?;
+
+        match extension.to_ascii_lowercase().as_str() {
+            "gz" | "gzip" => Some(Self::Gzip),
+            "zst" | "zstd" => Some(Self::Zstd),
+            "bz2" => Some(Self::Bzip2),
+            "xz" => Some(Self::Xz),
+            _ => None,
+        }
+    }
+
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+
+        match mime.as_str() {
+            "application/gzip" | "application/x-gzip" => Some(Self::Gzip),
+            "application/zstd" => Some(Self::Zstd),
+            "application/x-bzip2" => Some(Self::Bzip2),
+            "application/x-xz" => Some(Self::Xz),
+            _ => None,
+        }
+    }
+
+    fn from_content_encoding(encoding: &str) -> Option<Self> {
+        match encoding.to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            "br" => Some(Self::Brotli),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Decompress a byte stream
+    pub(crate) fn decode<'a>(
+        self,
+        stream: BoxStream<'a, Result<Bytes>>,
+    ) -> BoxStream<'a, Result<Bytes>> {
+        let reader = StreamReader::new(stream.map(|chunk| chunk.map_err(io::Error::other)));
+
+        let reader: Pin<Box<dyn AsyncRead + Send + 'a>> = match self {
+            Self::Gzip => {
+                let mut decoder = GzipDecoder::new(reader);
+                decoder.multiple_members(true);
+
+                Box::pin(decoder)
+            }
+            // HTTP deflate is a zlib stream
+            Self::Deflate => Box::pin(ZlibDecoder::new(reader)),
+            Self::Brotli => Box::pin(BrotliDecoder::new(reader)),
+            Self::Zstd => Box::pin(ZstdDecoder::new(reader)),
+            Self::Bzip2 => Box::pin(BzDecoder::new(reader)),
+            Self::Xz => Box::pin(XzDecoder::new(reader)),
+        };
+
+        ReaderStream::new(reader)
+            .map(|chunk| chunk.context("Failed to decompress the downloaded file"))
+            .boxed()
+    }
+}
+
+/// Compresses the downloaded file chunk by chunk before it is written to storage
+pub(crate) enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    pub(crate) fn new(options: &CompressOptions) -> Result<Self, HandlerError> {
+        match options.algorithm {
+            CompressionAlgorithm::Gzip => {
+                let level = match options.level {
+                    Some(level @ 0..=9) => flate2::Compression::new(level as u32),
+                    Some(level) => return Err(invalid_level(options.algorithm, level)),
+                    None => flate2::Compression::default(),
+                };
+
+                Ok(Self::Gzip(GzEncoder::new(Vec::new(), level)))
+            }
+            CompressionAlgorithm::Zstd => {
+                let level = match options.level {
+                    Some(level @ 1..=22) => level,
+                    Some(level) => return Err(invalid_level(options.algorithm, level)),
+                    None => zstd::DEFAULT_COMPRESSION_LEVEL,
+                };
+
+                let encoder = zstd::stream::write::Encoder::new(Vec::new(), level)
+                    .context("Failed to create zstd encoder")?;
+
+                Ok(Self::Zstd(encoder))
+            }
+        }
+    }
+
+    /// Compress a chunk and return the compressed output produced so far
+    pub(crate) fn encode(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let buffer = match self {
+            Self::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            Self::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+        };
+
+        Ok(Bytes::from(std::mem::take(buffer)))
+    }
+
+    /// Flush the remaining compressed output
+    pub(crate) fn finish(self) -> io::Result<Bytes> {
+        let buffer = match self {
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+
+        Ok(Bytes::from(buffer))
+    }
+}
+
+fn invalid_level(algorithm: CompressionAlgorithm, level: i32) -> HandlerError {
+    terminal(format!(
+        "Invalid {} compression level: {}",
+        algorithm.content_encoding(),
+        level
+    ))
+}
+
+/// Content encodings applied to the response in the order they have to be decoded.
+///
+/// The HTTP client does not decode responses on its own, so without this a gzip encoded response
+/// would be stored compressed even though its content type describes the decoded file.
+pub(crate) fn content_encodings(headers: &HeaderMap) -> Result<Vec<Compression>, HandlerError> {
+    let mut encodings = Vec::new();
+
+    for value in headers.get_all(CONTENT_ENCODING) {
+        let value = value.to_str().map_err(terminal)?;
+
+        for encoding in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            if encoding.eq_ignore_ascii_case("identity") {
+                continue;
+            }
+
+            let compression = Compression::from_content_encoding(encoding)
+                .ok_or_else(|| terminal(format!("Unsupported content encoding: {}", encoding)))?;
+
+            encodings.push(compression);
+        }
+    }
+
+    // Encodings are listed in the order they were applied
+    encodings.reverse();
+
+    Ok(encodings)
+}
+
+/// Strip the compression extension from a filename (eg. "dump.sql.gz" -> "dump.sql")
+pub(crate) fn decompressed_filename(filename: String) -> String {
+    match filename.rsplit_once('.') {
+        Some((stem, _))
+            if !matches!(stem, "" | "." | "..")
+                && Compression::from_filename(&filename).is_some() =>
+        {
+            stem.to_string()
+        }
+        _ => filename,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    /// Test compression detection from filenames and content types
+    #[test]
+    fn test_detect_compression() {
+        let cases = [
+            (Some("dump.sql.gz"), None, Some(Compression::Gzip)),
+            (Some("DUMP.SQL.GZ"), None, Some(Compression::Gzip)),
+            (Some("data.json.zst"), None, Some(Compression::Zstd)),
+            (Some("data.csv.bz2"), None, Some(Compression::Bzip2)),
+            (Some("image.raw.xz"), None, Some(Compression::Xz)),
+            (
+                Some("download"),
+                Some("application/gzip"),
+                Some(Compression::Gzip),
+            ),
+            (
+                Some("download"),
+                Some("application/x-bzip2; charset=binary"),
+                Some(Compression::Bzip2),
+            ),
+            (Some("file.txt"), Some("text/plain"), None),
+            (None, None, None),
+        ];
+
+        for (filename, content_type, expected) in cases {
+            assert_eq!(
+                Compression::detect(filename, content_type),
+                expected,
+                "filename: {:?}, content type: {:?}",
+                filename,
+                content_type
+            );
+        }
+    }
+
+    /// Test parsing the content encodings of a response
+    #[test]
+    fn test_content_encodings() {
+        let cases = [
+            (vec![], Some(vec![])),
+            (vec!["identity"], Some(vec![])),
+            (vec!["gzip"], Some(vec![Compression::Gzip])),
+            (
+                vec!["deflate, br"],
+                Some(vec![Compression::Brotli, Compression::Deflate]),
+            ),
+            (
+                vec!["x-gzip", "zstd"],
+                Some(vec![Compression::Zstd, Compression::Gzip]),
+            ),
+            (vec!["compress"], None),
+        ];
+
+        for (values, expected) in cases {
+            let mut headers = HeaderMap::new();
+
+            for value in &values {
+                headers.append(CONTENT_ENCODING, HeaderValue::from_static(value));
+            }
+
+            assert_eq!(
+                content_encodings(&headers).ok(),
+                expected,
+                "values: {:?}",
+                values
+            );
+        }
+    }
+
+    /// Test that compressed chunks decode to the original file
+    #[test]
+    fn test_encoder_roundtrip() {
+        let cases = [
+            (CompressionAlgorithm::Gzip, None, Compression::Gzip),
+            (CompressionAlgorithm::Gzip, Some(9), Compression::Gzip),
+            (CompressionAlgorithm::Zstd, None, Compression::Zstd),
+            (CompressionAlgorithm::Zstd, Some(19), Compression::Zstd),
+        ];
+
+        let chunks = ["id,name\n", "1,foo\n", "", "2,bar\n"];
+
+        for (algorithm, level, compression) in cases {
+            let mut encoder = Encoder::new(&CompressOptions { algorithm, level }).unwrap();
+
+            let mut encoded: Vec<Result<Bytes>> = chunks
+                .iter()
+                .map(|chunk| Ok(encoder.encode(chunk.as_bytes()).unwrap()))
+                .collect();
+            encoded.push(Ok(encoder.finish().unwrap()));
+
+            let decoded: Vec<Bytes> = futures::executor::block_on(
+                compression
+                    .decode(futures::stream::iter(encoded).boxed())
+                    .map(|chunk| chunk.unwrap())
+                    .collect(),
+            );
+
+            assert_eq!(
+                decoded.concat(),
+                chunks.concat().as_bytes(),
+                "algorithm: {:?}, level: {:?}",
+                algorithm,
+                level
+            );
+        }
+    }
+
+    /// Test that out of range compression levels are rejected
+    #[test]
+    fn test_encoder_invalid_level() {
+        let cases = [
+            (CompressionAlgorithm::Gzip, 10),
+            (CompressionAlgorithm::Gzip, -1),
+            (CompressionAlgorithm::Zstd, 0),
+            (CompressionAlgorithm::Zstd, 23),
+        ];
+
+        for (algorithm, level) in cases {
+            assert!(
+                Encoder::new(&CompressOptions {
+                    algorithm,
+                    level: Some(level)
+                })
+                .is_err(),
+                "algorithm: {:?}, level: {}",
+                algorithm,
+                level
+            );
+        }
+    }
+
+    /// Test stripping the compression extension from filenames
+    #[test]
+    fn test_decompressed_filename() {
+        let cases = [
+            ("dump.sql.gz", "dump.sql"),
+            ("archive.tar.zst", "archive.tar"),
+            ("data.bz2", "data"),
+            ("file.txt", "file.txt"),
+            (".gz", ".gz"),
+            ("..gz", "..gz"),
+            ("noext", "noext"),
+        ];
+
+        for (filename, expected) in cases {
+            assert_eq!(
+                decompressed_filename(filename.to_string()),
+                expected,
+                "filename: {}",
+                filename
+            );
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{egress::EgressPolicy, filename::FilenamePolicy};
+
+/// Service-wide defaults applied to every download
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Config {
+    /// Size of a segment in bytes for parallel segmented downloads (defaults to 8 MiB)
+    #[serde(default)]
+    pub segment_size: Option<u64>,
+
+    /// Number of segments downloaded concurrently (defaults to 1, which disables segmented downloads)
+    #[serde(default)]
+    pub concurrency: Option<usize>,
+
+    /// Upper bound of the segment size, also capping the size requested by callers (defaults to 64 MiB)
+    #[serde(default)]
+    pub max_segment_size: Option<u64>,
+
+    /// Upper bound of the number of segments downloaded concurrently, also capping the concurrency requested by callers (defaults to 16)
+    ///
+    /// Up to `max_segment_size × max_concurrency` bytes of a download are buffered in memory.
+    #[serde(default)]
+    pub max_concurrency: Option<usize>,
+
+    /// Maximum size of a downloaded file in bytes (unlimited by default)
+    #[serde(default)]
+    pub max_size: Option<u64>,
+
+    /// Prefix of the staging path for staged downloads, nested under the root prefix and tenant directory (defaults to ".incoming/")
+    #[serde(default)]
+    pub staging_prefix: Option<String>,
+
+    /// Maximum number of downloads running at the same time in a batch (defaults to 4)
+    #[serde(default)]
+    pub batch_concurrency: Option<usize>,
+
+    /// Upper bound of the number of downloads running at the same time in a batch, also capping the concurrency requested by callers (defaults to 32)
+    #[serde(default)]
+    pub max_batch_concurrency: Option<usize>,
+
+    /// Restate ingress URL used to report the progress of running downloads and to observe cancellation requests
+    /// (without it, progress is only recorded when a download starts and finishes, and downloads cannot be cancelled)
+    #[serde(default)]
+    pub ingress_url: Option<Url>,
+
+    /// Secret used to sign completion notifications with HMAC-SHA256 (notifications are not signed without it)
+    #[serde(default)]
+    pub notify_secret: Option<String>,
+
+    /// Minimum interval between progress reports of a running download (defaults to 1s)
+    #[serde(default, with = "humantime_serde")]
+    pub progress_interval: Option<Duration>,
+
+    /// Policy for requests sent to caller provided URLs (by default only HTTPS requests to public addresses are allowed)
+    #[serde(default)]
+    pub egress: EgressPolicy,
+
+    /// Storage URI patterns callers may write to when the service runs without a store (any URI is allowed when empty)
+    ///
+    /// Patterns are globs matched against the URI of every written file without its query (eg. `s3://bucket/exports/**`),
+    /// including staged files, sidecars and extracted archive entries, where `*` does not cross `/`. Query parameters are rejected when patterns are configured,
+    /// because they configure the storage (eg. its endpoint or credentials).
+    #[serde(default)]
+    pub allowed_storage_uris: Vec<String>,
+
+    /// Prefix all paths are confined to when the service runs with a store (eg. "downloads/")
+    #[serde(default)]
+    pub root_prefix: Option<String>,
+
+    /// Invocation header carrying the tenant ID when the service runs with a store
+    /// (paths are confined to a directory named after the tenant under the root prefix and requests without the header are rejected)
+    #[serde(default)]
+    pub tenant_header: Option<String>,
+
+    /// Policy for sanitizing filenames derived from the response (Content-Disposition header or URL)
+    #[serde(default)]
+    pub filename: FilenamePolicy,
+}
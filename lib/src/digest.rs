@@ -0,0 +1,216 @@
+use std::{collections::BTreeMap, fmt};
+
+use md5::Md5;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256, Sha512};
+
+/// Supported digest algorithms
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize, JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    Sha256,
+    Sha512,
+    Md5,
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Sha512 => "sha512",
+            DigestAlgorithm::Md5 => "md5",
+            DigestAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+pub(crate) fn default_digests() -> Vec<DigestAlgorithm> {
+    vec![DigestAlgorithm::Sha256]
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Expected digest of the downloaded file
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Checksum {
+    /// Digest algorithm
+    pub algorithm: DigestAlgorithm,
+    /// Hex encoded digest value
+    #[schemars(length(min = 1))]
+    pub value: String,
+}
+
+impl Checksum {
+    /// Check whether a hex encoded digest matches the expected value (case-insensitive)
+    pub fn matches(&self, digest: &str) -> bool {
+        self.value.eq_ignore_ascii_case(digest)
+    }
+}
+
+/// Incremental hasher for one of the supported digest algorithms
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Md5(Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub(crate) fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            DigestAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            DigestAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            DigestAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    /// Finalize the hasher and return the hex encoded digest
+    pub(crate) fn finalize(self) -> String {
+        match self {
+            Hasher::Sha256(h) => hex::encode(h.finalize()),
+            Hasher::Sha512(h) => hex::encode(h.finalize()),
+            Hasher::Md5(h) => hex::encode(h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Computes a set of digests over the same stream of data
+pub(crate) struct Digester {
+    hashers: Vec<(DigestAlgorithm, Hasher)>,
+}
+
+impl Digester {
+    pub(crate) fn new(algorithms: impl IntoIterator<Item = DigestAlgorithm>) -> Self {
+        let mut hashers: Vec<(DigestAlgorithm, Hasher)> = Vec::new();
+
+        for algorithm in algorithms {
+            if !hashers.iter().any(|(a, _)| *a == algorithm) {
+                hashers.push((algorithm, Hasher::new(algorithm)));
+            }
+        }
+
+        Self { hashers }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in self.hashers.iter_mut() {
+            hasher.update(data);
+        }
+    }
+
+    /// Finalize every hasher and return the hex encoded digests by algorithm
+    pub(crate) fn finalize(self) -> BTreeMap<DigestAlgorithm, String> {
+        self.hashers
+            .into_iter()
+            .map(|(algorithm, hasher)| (algorithm, hasher.finalize()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(algorithm: DigestAlgorithm, chunks: &[&[u8]]) -> String {
+        let mut hasher = Hasher::new(algorithm);
+
+        for chunk in chunks {
+            hasher.update(chunk);
+        }
+
+        hasher.finalize()
+    }
+
+    /// Test that every algorithm produces the well-known digest of "hello world",
+    /// regardless of how the input is chunked
+    #[test]
+    fn test_hasher_known_digests() {
+        let test_cases = vec![
+            (
+                DigestAlgorithm::Sha256,
+                "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+            ),
+            (
+                DigestAlgorithm::Sha512,
+                "309ecc489c12d6eb4cc40f50c902f2b4d0ed77ee511a7c7a9bcd3ca86d4cd86f989dd35bc5ff499670da34255b45b0cfd830e81f605dcf7dc5542e93ae9cd76f",
+            ),
+            (DigestAlgorithm::Md5, "5eb63bbbe01eeed093cb22bb8f5acdc3"),
+            (
+                DigestAlgorithm::Blake3,
+                "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
+            ),
+        ];
+
+        for (algorithm, expected) in test_cases {
+            assert_eq!(
+                digest(algorithm, &[b"hello world"]),
+                expected,
+                "Failed for algorithm: {}",
+                algorithm
+            );
+            assert_eq!(
+                digest(algorithm, &[b"hello", b" ", b"world"]),
+                expected,
+                "Failed for chunked input with algorithm: {}",
+                algorithm
+            );
+        }
+    }
+
+    /// Test that the digester computes each requested algorithm once
+    #[test]
+    fn test_digester_deduplicates_algorithms() {
+        let mut digester = Digester::new([
+            DigestAlgorithm::Md5,
+            DigestAlgorithm::Sha256,
+            DigestAlgorithm::Md5,
+        ]);
+        digester.update(b"hello world");
+
+        let digests = digester.finalize();
+
+        assert_eq!(digests.len(), 2);
+        assert_eq!(
+            digests.get(&DigestAlgorithm::Md5).map(String::as_str),
+            Some("5eb63bbbe01eeed093cb22bb8f5acdc3")
+        );
+        assert_eq!(
+            digests.get(&DigestAlgorithm::Sha256).map(String::as_str),
+            Some("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9")
+        );
+    }
+
+    /// Test that checksum comparison ignores the case of the hex digits
+    #[test]
+    fn test_checksum_matches_case_insensitive() {
+        let checksum = Checksum {
+            algorithm: DigestAlgorithm::Md5,
+            value: "5EB63BBBE01EEED093CB22BB8F5ACDC3".to_string(),
+        };
+
+        assert!(checksum.matches("5eb63bbbe01eeed093cb22bb8f5acdc3"));
+        assert!(!checksum.matches("00000000000000000000000000000000"));
+    }
+}
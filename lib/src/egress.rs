@@ -0,0 +1,438 @@
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use globset::GlobBuilder;
+use regex::RegexBuilder;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect,
+};
+use serde::{Deserialize, Serialize};
+use url::{Host, Url};
+
+/// Maximum number of redirects followed by clients enforcing the policy (same as the reqwest default)
+const MAX_REDIRECTS: usize = 10;
+
+/// Policy for requests sent to caller provided URLs (downloads and webhooks).
+///
+/// By default only HTTPS URLs are allowed and requests to private, loopback, link-local
+/// and other non-public addresses (including cloud metadata endpoints) are rejected.
+///
+/// The scheme, port and host of the URL are checked before every request and redirect.
+/// Hostnames are checked against the non-public address ranges when they are resolved,
+/// which requires a client built with [`EgressPolicy::client_builder`]:
+/// the client only connects to the addresses that passed the check, so DNS rebinding cannot bypass the policy.
+/// Such a client never connects through a proxy from the environment, which would bypass the check.
+///
+/// Host patterns are case-insensitive globs (eg. `*.example.com`, which does not match `example.com` itself)
+/// or regular expressions prefixed with `regex:` (eg. `regex:^files[0-9]+\.example\.com$`).
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EgressPolicy {
+    /// Allowed URL schemes (defaults to https)
+    #[serde(default = "default_schemes")]
+    pub allowed_schemes: Vec<String>,
+
+    /// Allowed ports (any port is allowed when empty)
+    #[serde(default)]
+    pub allowed_ports: Vec<u16>,
+
+    /// Host patterns requests are allowed to (any host is allowed when empty)
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+
+    /// Host patterns requests are never sent to (takes precedence over the allowed hosts)
+    #[serde(default)]
+    pub denied_hosts: Vec<String>,
+
+    /// Allow requests to non-public addresses (disables the protection)
+    #[serde(default)]
+    pub allow_private: bool,
+
+    /// Host patterns allowed to resolve to non-public addresses (eg. internal services)
+    #[serde(default)]
+    pub private_hosts: Vec<String>,
+}
+
+impl Default for EgressPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: default_schemes(),
+            allowed_ports: Vec::new(),
+            allowed_hosts: Vec::new(),
+            denied_hosts: Vec::new(),
+            allow_private: false,
+            private_hosts: Vec::new(),
+        }
+    }
+}
+
+fn default_schemes() -> Vec<String> {
+    vec!["https".to_string()]
+}
+
+impl EgressPolicy {
+    /// Create an HTTP client builder that enforces the policy on every connection and redirect
+    ///
+    /// The client ignores the `HTTP_PROXY`, `HTTPS_PROXY` and `ALL_PROXY` environment variables:
+    /// a proxy resolves the hostname itself, so the resolved addresses could not be checked.
+    pub fn client_builder(&self) -> reqwest::ClientBuilder {
+        let policy = Arc::new(self.clone());
+
+        reqwest::Client::builder()
+            .no_proxy()
+            .dns_resolver(Arc::new(EgressResolver {
+                policy: policy.clone(),
+            }))
+            .redirect(redirect_policy(policy))
+    }
+
+    /// Check a URL before a request is sent to it
+    pub fn check_url(&self, url: &Url) -> Result<(), EgressError> {
+        let scheme = url.scheme();
+
+        if !self
+            .allowed_schemes
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(scheme))
+        {
+            return Err(EgressError::Scheme(scheme.to_string()));
+        }
+
+        let (host, ip) = match url.host() {
+            Some(Host::Domain(domain)) => (domain.to_string(), None),
+            Some(Host::Ipv4(ip)) => (ip.to_string(), Some(IpAddr::V4(ip))),
+            Some(Host::Ipv6(ip)) => (ip.to_string(), Some(IpAddr::V6(ip))),
+            None => return Err(EgressError::MissingHost),
+        };
+
+        if let Some(port) = url.port_or_known_default()
+            && !self.allowed_ports.is_empty()
+            && !self.allowed_ports.contains(&port)
+        {
+            return Err(EgressError::Port(port));
+        }
+
+        if matches_any(&self.denied_hosts, &host)? {
+            return Err(EgressError::DeniedHost(host));
+        }
+
+        if !self.allowed_hosts.is_empty() && !matches_any(&self.allowed_hosts, &host)? {
+            return Err(EgressError::HostNotAllowed(host));
+        }
+
+        // Hostnames are checked against the non-public address ranges when they are resolved
+        match ip {
+            Some(ip) if !is_public(ip) && !self.allows_private(&host)? => {
+                Err(EgressError::PrivateAddress(host))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn allows_private(&self, host: &str) -> Result<bool, EgressError> {
+        Ok(self.allow_private || matches_any(&self.private_hosts, host)?)
+    }
+}
+
+/// Check whether a host matches any of the patterns
+fn matches_any(patterns: &[String], host: &str) -> Result<bool, EgressError> {
+    for pattern in patterns {
+        let invalid = |err: &dyn fmt::Display| EgressError::InvalidPattern {
+            pattern: pattern.clone(),
+            error: err.to_string(),
+        };
+
+        let matches = match pattern.strip_prefix("regex:") {
+            Some(regex) => RegexBuilder::new(regex)
+                .case_insensitive(true)
+                .build()
+                .map_err(|err| invalid(&err))?
+                .is_match(host),
+            None => GlobBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()
+                .map_err(|err| invalid(&err))?
+                .compile_matcher()
+                .is_match(host),
+        };
+
+        if matches {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Error returned when a request is rejected by the egress policy
+#[derive(Debug)]
+pub enum EgressError {
+    Scheme(String),
+    MissingHost,
+    Port(u16),
+    DeniedHost(String),
+    HostNotAllowed(String),
+    PrivateAddress(String),
+    InvalidPattern { pattern: String, error: String },
+}
+
+impl fmt::Display for EgressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EgressError::Scheme(scheme) => write!(f, "URL scheme {} is not allowed", scheme),
+            EgressError::MissingHost => write!(f, "URL has no host"),
+            EgressError::Port(port) => write!(f, "Port {} is not allowed", port),
+            EgressError::DeniedHost(host) => write!(f, "Host {} is denied", host),
+            EgressError::HostNotAllowed(host) => {
+                write!(f, "Host {} is not in the allowed hosts", host)
+            }
+            EgressError::PrivateAddress(host) => write!(
+                f,
+                "Requests to {} are not allowed: the host does not resolve to a public address",
+                host
+            ),
+            EgressError::InvalidPattern { pattern, error } => {
+                write!(f, "Invalid host pattern {}: {}", pattern, error)
+            }
+        }
+    }
+}
+
+impl Error for EgressError {}
+
+/// Find the egress policy violation that caused an error
+pub(crate) fn egress_error<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a EgressError> {
+    let mut source = Some(err);
+
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<EgressError>() {
+            return Some(err);
+        }
+
+        source = err.source();
+    }
+
+    None
+}
+
+/// Resolves hostnames and drops the addresses rejected by the egress policy
+struct EgressResolver {
+    policy: Arc<EgressPolicy>,
+}
+
+impl Resolve for EgressResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        let allowed = self.policy.allows_private(&host);
+
+        Box::pin(async move {
+            let allowed = allowed?;
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| allowed || is_public(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+
+            if addrs.is_empty() {
+                return Err(EgressError::PrivateAddress(host).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn redirect_policy(policy: Arc<EgressPolicy>) -> redirect::Policy {
+    redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+
+        match policy.check_url(attempt.url()) {
+            Ok(()) => attempt.follow(),
+            Err(err) => attempt.error(err),
+        }
+    })
+}
+
+/// Check whether an IP address is publicly routable
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network" (0.0.0.0/8)
+        || a == 0
+        // Shared address space (100.64.0.0/10)
+        || (a == 100 && (b & 0b1100_0000) == 64)
+        // IETF protocol assignments (192.0.0.0/24)
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking (198.18.0.0/15)
+        || (a == 198 && (b & 0xfe) == 18)
+        // Reserved (240.0.0.0/4)
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    // IPv4-mapped (::ffff:0:0/96) and NAT64 (64:ff9b::/96) addresses reach the embedded IPv4 address
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return is_public_v4(ip);
+    }
+
+    let segments = ip.segments();
+
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [a, b] = segments[6].to_be_bytes();
+        let [c, d] = segments[7].to_be_bytes();
+
+        return is_public_v4(Ipv4Addr::new(a, b, c, d));
+    }
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // Site-local (fec0::/10, deprecated)
+        || (segments[0] & 0xffc0) == 0xfec0
+        // Documentation (2001:db8::/32)
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test the classification of public and non-public addresses
+    #[test]
+    fn test_is_public() {
+        let cases = [
+            ("8.8.8.8", true),
+            ("1.1.1.1", true),
+            ("127.0.0.1", false),
+            ("10.1.2.3", false),
+            ("172.16.0.1", false),
+            ("192.168.1.1", false),
+            ("169.254.169.254", false),
+            ("100.64.0.1", false),
+            ("0.0.0.0", false),
+            ("255.255.255.255", false),
+            ("2606:4700:4700::1111", true),
+            ("::1", false),
+            ("::", false),
+            ("fe80::1", false),
+            ("fd00:ec2::254", false),
+            ("::ffff:127.0.0.1", false),
+            ("::ffff:8.8.8.8", true),
+            ("64:ff9b::a9fe:a9fe", false),
+        ];
+
+        for (ip, expected) in cases {
+            assert_eq!(is_public(ip.parse().unwrap()), expected, "ip: {}", ip);
+        }
+    }
+
+    /// Test checking URLs with non-public addresses against the policy
+    #[test]
+    fn test_check_url_private() {
+        let policy = EgressPolicy {
+            allowed_schemes: vec!["http".to_string(), "https".to_string()],
+            private_hosts: vec!["10.0.0.5".to_string()],
+            ..Default::default()
+        };
+
+        let cases = [
+            ("https://example.com/file.pdf", true),
+            ("http://8.8.8.8/file.pdf", true),
+            ("http://169.254.169.254/latest/meta-data/", false),
+            ("http://127.0.0.1:9070/", false),
+            ("http://[::1]/", false),
+            ("http://10.0.0.5/file.pdf", true),
+            ("http://10.0.0.6/file.pdf", false),
+        ];
+
+        for (url, expected) in cases {
+            assert_eq!(
+                policy.check_url(&Url::parse(url).unwrap()).is_ok(),
+                expected,
+                "url: {}",
+                url
+            );
+        }
+
+        let policy = EgressPolicy {
+            allowed_schemes: vec!["http".to_string()],
+            allow_private: true,
+            ..Default::default()
+        };
+
+        assert!(
+            policy
+                .check_url(&Url::parse("http://127.0.0.1/").unwrap())
+                .is_ok()
+        );
+    }
+
+    /// Test checking URLs against the scheme, port and host lists of the policy
+    #[test]
+    fn test_check_url_lists() {
+        let policy = EgressPolicy {
+            allowed_ports: vec![443, 8443],
+            allowed_hosts: vec![
+                "*.example.com".to_string(),
+                "regex:^files[0-9]+\\.example\\.org$".to_string(),
+            ],
+            denied_hosts: vec!["internal.example.com".to_string()],
+            ..Default::default()
+        };
+
+        let cases = [
+            ("https://cdn.example.com/file.pdf", true),
+            ("https://CDN.Example.com/file.pdf", true),
+            ("https://a.b.example.com/file.pdf", true),
+            ("https://cdn.example.com:8443/file.pdf", true),
+            ("https://files42.example.org/file.pdf", true),
+            ("http://cdn.example.com/file.pdf", false),
+            ("ftp://cdn.example.com/file.pdf", false),
+            ("https://cdn.example.com:9000/file.pdf", false),
+            ("https://example.com/file.pdf", false),
+            ("https://files.example.org/file.pdf", false),
+            ("https://internal.example.com/file.pdf", false),
+            ("https://evil.com/file.pdf", false),
+        ];
+
+        for (url, expected) in cases {
+            assert_eq!(
+                policy.check_url(&Url::parse(url).unwrap()).is_ok(),
+                expected,
+                "url: {}",
+                url
+            );
+        }
+
+        let policy = EgressPolicy {
+            allowed_hosts: vec!["[invalid".to_string()],
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            policy.check_url(&Url::parse("https://example.com/").unwrap()),
+            Err(EgressError::InvalidPattern { .. })
+        ));
+    }
+}
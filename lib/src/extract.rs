@@ -0,0 +1,694 @@
+use std::{fmt, io};
+
+use anyhow::{Context as _, Result};
+use async_compression::tokio::bufread::{GzipDecoder, XzDecoder, ZstdDecoder};
+use async_zip::base::read::stream::ZipFileReader;
+use bytes::Bytes;
+use futures::{SinkExt as _, StreamExt as _, channel::mpsc, stream::BoxStream};
+use opendal::{Operator, Writer};
+use restate_sdk::errors::HandlerError;
+use tokio::io::{AsyncBufRead, AsyncRead};
+use tokio_tar::Archive;
+use tokio_util::{
+    compat::FuturesAsyncReadCompatExt as _,
+    io::{ReaderStream, StreamReader},
+};
+
+use crate::{
+    common::{
+        CheckPath, ExtractedEntry, StreamedFile, publish, staging_path, terminal, verify_checksum,
+    },
+    digest::{Checksum, DigestAlgorithm, Digester},
+    progress::{ProgressReporter, cancelled},
+};
+
+/// Number of leading bytes inspected to detect the archive format (the tar magic ends at offset 262)
+const MAGIC_LEN: usize = 262;
+
+/// Number of chunks buffered between the download and the extraction
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Supported archive formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarZst,
+    TarXz,
+}
+
+impl ArchiveFormat {
+    /// Detect the archive format from the filename, the content type or the leading bytes of the archive
+    fn detect(filename: &str, content_type: Option<&str>, magic: &[u8]) -> Option<Self> {
+        Self::from_filename(filename)
+            .or_else(|| content_type.and_then(Self::from_content_type))
+            .or_else(|| Self::from_magic(magic))
+    }
+
+    fn from_filename(filename: &str) -> Option<Self> {
+        let filename = filename.to_ascii_lowercase();
+
+        [
+            (".tar.gz", ArchiveFormat::TarGz),
+            (".tgz", ArchiveFormat::TarGz),
+            (".tar.zst", ArchiveFormat::TarZst),
+            (".tzst", ArchiveFormat::TarZst),
+            (".tar.xz", ArchiveFormat::TarXz),
+            (".txz", ArchiveFormat::TarXz),
+            (".tar", ArchiveFormat::Tar),
+            (".zip", ArchiveFormat::Zip),
+        ]
+        .into_iter()
+        .find(|(extension, _)| filename.ends_with(extension))
+        .map(|(_, format)| format)
+    }
+
+    /// Compressed content types are assumed to contain a tarball
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+
+        match mime.as_str() {
+            "application/zip" | "application/x-zip-compressed" => Some(ArchiveFormat::Zip),
+            "application/x-tar" => Some(ArchiveFormat::Tar),
+            "application/gzip" | "application/x-gzip" | "application/x-gtar" => {
+                Some(ArchiveFormat::TarGz)
+            }
+            "application/zstd" | "application/x-zstd" => Some(ArchiveFormat::TarZst),
+            "application/x-xz" => Some(ArchiveFormat::TarXz),
+            _ => None,
+        }
+    }
+
+    fn from_magic(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(b"PK\x03\x04") {
+            Some(ArchiveFormat::Zip)
+        } else if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(ArchiveFormat::TarGz)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(ArchiveFormat::TarZst)
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(ArchiveFormat::TarXz)
+        } else if magic.get(257..262) == Some(b"ustar") {
+            Some(ArchiveFormat::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::TarXz => "tar.xz",
+        })
+    }
+}
+
+/// Detect the format of the archive in the stream from the filename of the source, its content type
+/// or its magic bytes (in that order).
+///
+/// The leading bytes of the stream are buffered to look for magic bytes,
+/// so the returned stream replays them before the rest of the archive.
+pub(crate) async fn detect_archive(
+    stream: BoxStream<'static, Result<Bytes>>,
+    filename: &str,
+    content_type: Option<&str>,
+) -> Result<(ArchiveFormat, BoxStream<'static, Result<Bytes>>), HandlerError> {
+    // Archives shorter than the magic bytes end the stream before it is replayed
+    let mut stream = stream.fuse();
+    let mut head = Vec::new();
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+
+    while magic.len() < MAGIC_LEN {
+        let Some(chunk) = stream.next().await else {
+            break;
+        };
+        let chunk = chunk.context("Failed to read chunk from HTTP response")?;
+
+        magic.extend_from_slice(&chunk[..chunk.len().min(MAGIC_LEN - magic.len())]);
+        head.push(Ok(chunk));
+    }
+
+    let format = ArchiveFormat::detect(filename, content_type, &magic).ok_or_else(|| {
+        terminal("Unsupported archive format (supported formats are zip, tar, tar.gz, tar.zst and tar.xz)")
+    })?;
+
+    tracing::debug!(%format, "Detected archive format");
+
+    Ok((format, futures::stream::iter(head).chain(stream).boxed()))
+}
+
+/// Writes the entries of an archive under a prefix in storage
+pub(crate) struct Extractor<'a> {
+    operator: &'a Operator,
+    prefix: &'a str,
+    max_size: Option<u64>,
+    check_path: CheckPath<'a>,
+    /// Root directory and staging prefix of the staged entries
+    staging: Option<(&'a str, &'a str)>,
+    extracted: u64,
+    entries: Vec<ExtractedEntry>,
+}
+
+impl<'a> Extractor<'a> {
+    pub(crate) fn new(
+        operator: &'a Operator,
+        prefix: &'a str,
+        max_size: Option<u64>,
+        check_path: CheckPath<'a>,
+    ) -> Self {
+        Self {
+            operator,
+            prefix,
+            max_size,
+            check_path,
+            staging: None,
+            extracted: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Write the entries to the staging path and move them to their final path once the archive is verified
+    pub(crate) fn with_staging(mut self, root: &'a str, prefix: &'a str) -> Self {
+        self.staging = Some((root, prefix));
+        self
+    }
+
+    /// Path an entry is written to before the archive is verified
+    fn write_path(&self, path: &str) -> Result<String, HandlerError> {
+        match self.staging {
+            Some((root, prefix)) => staging_path(root, prefix, path),
+            None => Ok(path.to_string()),
+        }
+    }
+
+    async fn extract<R>(&mut self, format: ArchiveFormat, reader: R) -> Result<(), HandlerError>
+    where
+        R: AsyncBufRead + Unpin + Send,
+    {
+        match format {
+            ArchiveFormat::Zip => self.extract_zip(reader).await,
+            ArchiveFormat::Tar => self.extract_tar(reader).await,
+            ArchiveFormat::TarGz => {
+                let mut decoder = GzipDecoder::new(reader);
+                decoder.multiple_members(true);
+
+                self.extract_tar(decoder).await
+            }
+            ArchiveFormat::TarZst => self.extract_tar(ZstdDecoder::new(reader)).await,
+            ArchiveFormat::TarXz => self.extract_tar(XzDecoder::new(reader)).await,
+        }
+    }
+
+    async fn extract_tar<R>(&mut self, reader: R) -> Result<(), HandlerError>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let mut archive = Archive::new(reader);
+        let mut entries = archive.entries().map_err(archive_error)?;
+
+        while let Some(entry) = entries.next().await {
+            let entry = entry.map_err(archive_error)?;
+
+            // Directories are implied by the paths of the files, links are not followed
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let name =
+                String::from_utf8_lossy(&entry.path_bytes().map_err(archive_error)?).into_owned();
+
+            self.write_entry(&name, entry).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn extract_zip<R>(&mut self, reader: R) -> Result<(), HandlerError>
+    where
+        R: AsyncBufRead + Unpin + Send,
+    {
+        let mut zip = ZipFileReader::with_tokio(reader);
+
+        while let Some(mut next) = zip.next_with_entry().await.map_err(archive_error)? {
+            let entry = next.reader().entry();
+            let name = entry
+                .filename()
+                .as_str()
+                .map_err(archive_error)?
+                .to_string();
+
+            if !entry.dir().map_err(archive_error)? {
+                self.write_entry(&name, next.reader_mut().compat()).await?;
+            }
+
+            zip = next.skip().await.map_err(archive_error)?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_entry<R>(&mut self, name: &str, reader: R) -> Result<(), HandlerError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let Some(path) = entry_path(self.prefix, name)? else {
+            return Ok(());
+        };
+
+        (self.check_path)(&path)?;
+
+        let write_path = self.write_path(&path)?;
+
+        if write_path != path {
+            (self.check_path)(&write_path)?;
+        }
+
+        let mut writer = self
+            .operator
+            .writer(&write_path)
+            .await
+            .context("Failed to create storage writer")?;
+
+        let size = match self.copy(reader, &mut writer).await {
+            Ok(size) => size,
+            Err(err) => {
+                if let Err(abort_err) = writer.abort().await {
+                    tracing::warn!(error = %abort_err, path = write_path, "Failed to abort storage upload");
+                }
+
+                return Err(err);
+            }
+        };
+
+        writer
+            .close()
+            .await
+            .context("Failed to finalize storage upload")?;
+
+        // Later entries with the same path overwrite earlier ones
+        self.entries.retain(|entry| entry.path != path);
+        self.entries.push(ExtractedEntry { path, size });
+
+        Ok(())
+    }
+
+    async fn copy<R>(&mut self, reader: R, writer: &mut Writer) -> Result<u64, HandlerError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut stream = ReaderStream::new(reader);
+        let mut size = 0u64;
+
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(archive_error)?;
+
+            size += chunk.len() as u64;
+            self.extracted += chunk.len() as u64;
+
+            if let Some(max_size) = self.max_size
+                && self.extracted > max_size
+            {
+                return Err(terminal(format!(
+                    "Extracted files exceed the maximum size of {} bytes",
+                    max_size
+                )));
+            }
+
+            writer
+                .write(chunk)
+                .await
+                .context("Failed to write chunk to storage")?;
+        }
+
+        Ok(size)
+    }
+
+    /// Move the staged entries to their final paths
+    async fn publish(&self) -> Result<(), HandlerError> {
+        if self.staging.is_none() {
+            return Ok(());
+        }
+
+        for entry in &self.entries {
+            publish(self.operator, &self.write_path(&entry.path)?, &entry.path).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Delete the entries written so far
+    async fn cleanup(&self) {
+        for entry in &self.entries {
+            let Ok(path) = self.write_path(&entry.path) else {
+                continue;
+            };
+
+            if let Err(err) = self.operator.delete(&path).await {
+                tracing::warn!(error = %err, path, "Failed to delete extracted file");
+            }
+        }
+    }
+}
+
+/// Stream an archive from the response and write its entries to storage.
+///
+/// Digests, checksum and progress apply to the downloaded archive, while the maximum size limits the extracted files.
+/// The archive is read while it is being downloaded, and the entries written so far are deleted if anything fails.
+/// Staged entries are only moved to their final paths once the whole archive is verified.
+pub(crate) async fn extract_file(
+    stream: BoxStream<'static, Result<Bytes>>,
+    mut extractor: Extractor<'_>,
+    format: ArchiveFormat,
+    checksum: Option<&Checksum>,
+    digests: &[DigestAlgorithm],
+    progress: Option<ProgressReporter>,
+) -> Result<StreamedFile, HandlerError> {
+    let mut digester = Digester::new(digests.iter().copied().chain(checksum.map(|c| c.algorithm)));
+
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+    let result = futures::future::try_join(
+        pump(stream, tx, &mut digester, progress),
+        extractor.extract(format, StreamReader::new(rx)),
+    )
+    .await;
+
+    let result = result.and_then(|(size, ())| {
+        let digests = digester.finalize();
+
+        verify_checksum(checksum, &digests)?;
+
+        Ok((size, digests))
+    });
+
+    let result = match result {
+        Ok(file) => extractor.publish().await.map(|()| file),
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok((size, digests)) => Ok(StreamedFile {
+            path: extractor.prefix.to_string(),
+            size,
+            digests,
+            entries: extractor.entries,
+        }),
+        Err(err) => {
+            extractor.cleanup().await;
+
+            Err(err)
+        }
+    }
+}
+
+/// Feed the downloaded archive to the extraction while hashing it and reporting progress
+async fn pump(
+    mut stream: BoxStream<'static, Result<Bytes>>,
+    mut tx: mpsc::Sender<io::Result<Bytes>>,
+    digester: &mut Digester,
+    mut progress: Option<ProgressReporter>,
+) -> Result<u64, HandlerError> {
+    let mut size = 0u64;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                // Make sure the extraction does not mistake a truncated archive for a complete one
+                let _ = tx.send(Err(io::Error::other(err.to_string()))).await;
+
+                return Err(err
+                    .context("Failed to read chunk from HTTP response")
+                    .into());
+            }
+        };
+
+        size += chunk.len() as u64;
+        digester.update(&chunk);
+
+        if let Some(progress) = progress.as_mut()
+            && progress.report(size).await
+        {
+            let _ = tx.send(Err(io::Error::other("Download cancelled"))).await;
+
+            return Err(cancelled());
+        }
+
+        // The extraction may finish before the end of the archive (eg. padding), the rest is still hashed
+        if !tx.is_closed() {
+            let _ = tx.send(Ok(chunk)).await;
+        }
+    }
+
+    Ok(size)
+}
+
+fn archive_error<E: fmt::Display>(e: E) -> HandlerError {
+    terminal(format!("Failed to read archive: {}", e))
+}
+
+/// Resolve the storage path of an archive entry under the prefix.
+///
+/// Empty and `.` segments are dropped (so absolute entries stay under the prefix),
+/// and entries with `..` segments are rejected.
+fn entry_path(prefix: &str, name: &str) -> Result<Option<String>, HandlerError> {
+    let mut segments = Vec::new();
+
+    for segment in name.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                return Err(terminal(format!(
+                    "Archive entry escapes the target directory: {}",
+                    name
+                )));
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    if segments.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(format!("{}{}", prefix, segments.join("/"))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        futures::executor::block_on(async {
+            let mut builder = tokio_tar::Builder::new_non_terminated(Vec::new());
+
+            for (name, data) in entries {
+                let mut header = tokio_tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+
+                builder.append_data(&mut header, name, *data).await.unwrap();
+            }
+
+            builder.into_inner().await.unwrap()
+        })
+    }
+
+    fn extract(
+        extractor: Extractor<'_>,
+        archive: Vec<u8>,
+        checksum: Option<&Checksum>,
+    ) -> Result<StreamedFile, HandlerError> {
+        let stream = futures::stream::iter([Ok(Bytes::from(archive))]).boxed();
+
+        futures::executor::block_on(extract_file(
+            stream,
+            extractor,
+            ArchiveFormat::Tar,
+            checksum,
+            &[],
+            None,
+        ))
+    }
+
+    fn exists(operator: &Operator, path: &str) -> bool {
+        futures::executor::block_on(operator.exists(path)).unwrap()
+    }
+
+    /// Test that the archive format is detected from the filename, the content type and the magic bytes (in that order)
+    #[test]
+    fn test_detect_archive_format() {
+        let mut tar = vec![0u8; MAGIC_LEN];
+        tar[257..262].copy_from_slice(b"ustar");
+
+        let test_cases: Vec<(&str, Option<&str>, &[u8], _)> = vec![
+            ("data.tar.gz", None, b"", Some(ArchiveFormat::TarGz)),
+            ("DATA.TGZ", None, b"", Some(ArchiveFormat::TarGz)),
+            ("data.tar.zst", None, b"", Some(ArchiveFormat::TarZst)),
+            ("data.txz", None, b"", Some(ArchiveFormat::TarXz)),
+            ("data.tar", None, b"", Some(ArchiveFormat::Tar)),
+            (
+                "data.zip",
+                Some("application/x-tar"),
+                b"",
+                Some(ArchiveFormat::Zip),
+            ),
+            (
+                "download",
+                Some("application/zip; charset=binary"),
+                b"",
+                Some(ArchiveFormat::Zip),
+            ),
+            (
+                "download",
+                Some("application/gzip"),
+                b"",
+                Some(ArchiveFormat::TarGz),
+            ),
+            (
+                "download",
+                None,
+                b"PK\x03\x04rest",
+                Some(ArchiveFormat::Zip),
+            ),
+            (
+                "download",
+                None,
+                &[0x1f, 0x8b, 0x08],
+                Some(ArchiveFormat::TarGz),
+            ),
+            (
+                "download",
+                None,
+                &[0x28, 0xb5, 0x2f, 0xfd, 0x00],
+                Some(ArchiveFormat::TarZst),
+            ),
+            ("download", None, &tar, Some(ArchiveFormat::Tar)),
+            ("download", Some("text/plain"), b"hello", None),
+        ];
+
+        for (filename, content_type, magic, expected) in test_cases {
+            assert_eq!(
+                ArchiveFormat::detect(filename, content_type, magic),
+                expected,
+                "Failed for filename '{}' and content type {:?}",
+                filename,
+                content_type
+            );
+        }
+    }
+
+    /// Test that entry paths are nested under the prefix and cannot escape it
+    #[test]
+    fn test_entry_path() {
+        let test_cases = vec![
+            ("", "file.txt", Some("file.txt")),
+            ("downloads/", "dir/file.txt", Some("downloads/dir/file.txt")),
+            (
+                "downloads/",
+                "./dir//file.txt",
+                Some("downloads/dir/file.txt"),
+            ),
+            ("downloads/", "/etc/passwd", Some("downloads/etc/passwd")),
+            (
+                "downloads/",
+                "dir\\file.txt",
+                Some("downloads/dir/file.txt"),
+            ),
+            ("downloads/", "./", None),
+        ];
+
+        for (prefix, name, expected) in test_cases {
+            assert_eq!(
+                entry_path(prefix, name).unwrap().as_deref(),
+                expected,
+                "Failed for entry '{}'",
+                name
+            );
+        }
+
+        assert!(entry_path("downloads/", "../file.txt").is_err());
+        assert!(entry_path("downloads/", "dir/../../file.txt").is_err());
+    }
+
+    /// Test that extracted paths are checked before they are written
+    #[test]
+    fn test_extract_file_checks_paths() {
+        let operator = testing::operator();
+        let archive = tar(&[("a.csv", b"a"), ("nested/x.sh", b"x")]);
+
+        let check_path = |path: &str| {
+            if path.trim_start_matches("exports/").contains('/') {
+                return Err(terminal(format!("Writing to {} is not allowed", path)));
+            }
+
+            Ok(())
+        };
+
+        let extractor = Extractor::new(&operator, "exports/", None, &check_path);
+
+        assert!(extract(extractor, archive, None).is_err());
+        assert!(!exists(&operator, "exports/a.csv"));
+        assert!(!exists(&operator, "exports/nested/x.sh"));
+
+        let archive = tar(&[("a.csv", b"a"), ("b.csv", b"b")]);
+        let extractor = Extractor::new(&operator, "exports/", None, &check_path);
+        let file = extract(extractor, archive, None).unwrap();
+
+        assert_eq!(file.entries.len(), 2);
+        assert!(exists(&operator, "exports/a.csv"));
+        assert!(exists(&operator, "exports/b.csv"));
+    }
+
+    /// Test that staged entries only become visible at their final paths once the archive is verified
+    #[test]
+    fn test_extract_file_staged() {
+        let operator = testing::operator();
+        let archive = tar(&[("a.csv", b"a"), ("dir/b.csv", b"b"), ("a.csv", b"c")]);
+
+        let mismatch = Checksum {
+            algorithm: DigestAlgorithm::Sha256,
+            value: "0".repeat(64),
+        };
+
+        let extractor = Extractor::new(&operator, "exports/", None, &|_| Ok(()))
+            .with_staging("exports/", ".incoming/");
+
+        assert!(extract(extractor, archive.clone(), Some(&mismatch)).is_err());
+        assert!(!exists(&operator, "exports/a.csv"));
+        assert!(!exists(&operator, "exports/.incoming/a.csv"));
+        assert!(!exists(&operator, "exports/.incoming/dir/b.csv"));
+
+        let extractor = Extractor::new(&operator, "exports/", None, &|_| Ok(()))
+            .with_staging("exports/", ".incoming/");
+        let file = extract(extractor, archive, None).unwrap();
+
+        let entries: Vec<_> = file
+            .entries
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry.size))
+            .collect();
+
+        assert_eq!(
+            entries,
+            vec![("exports/dir/b.csv", 1), ("exports/a.csv", 1)]
+        );
+        assert_eq!(
+            futures::executor::block_on(operator.read("exports/a.csv"))
+                .unwrap()
+                .to_vec(),
+            b"c"
+        );
+        assert!(exists(&operator, "exports/dir/b.csv"));
+        assert!(!exists(&operator, "exports/.incoming/a.csv"));
+        assert!(!exists(&operator, "exports/.incoming/dir/b.csv"));
+    }
+}
@@ -0,0 +1,486 @@
+use percent_encoding::percent_decode_str;
+use restate_sdk::errors::HandlerError;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use unicode_normalization::UnicodeNormalization as _;
+
+use crate::common::terminal;
+
+/// Placeholder in the fallback name replaced by a hash of the URL
+const HASH_PLACEHOLDER: &str = "{hash}";
+
+/// Number of hex characters of the URL hash used in fallback names
+const HASH_LEN: usize = 16;
+
+/// Maximum length of an extension preserved when a filename is truncated
+const MAX_EXTENSION_LEN: usize = 16;
+
+/// Device names reserved on Windows (with or without an extension)
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Policy for sanitizing filenames derived from the response (Content-Disposition header or URL)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FilenamePolicy {
+    /// Characters allowed in filenames (defaults to unicode)
+    #[serde(default)]
+    pub charset: Charset,
+
+    /// Replacement for characters that are not allowed (defaults to "_", characters of the replacement that are not allowed are dropped)
+    #[serde(default = "default_replacement")]
+    pub replacement: String,
+
+    /// Maximum length of a filename in bytes (defaults to 255)
+    #[serde(default = "default_max_length")]
+    pub max_length: usize,
+
+    /// Percent-decode filenames derived from the URL (defaults to true)
+    #[serde(default = "default_percent_decode")]
+    pub percent_decode: bool,
+
+    /// Unicode normalization form applied to filenames (defaults to NFC)
+    #[serde(default)]
+    pub normalization: Normalization,
+
+    /// Name used when no usable filename can be derived (defaults to "download", "{hash}" is replaced by a hash of the URL)
+    #[serde(default = "default_fallback")]
+    pub fallback: String,
+}
+
+impl Default for FilenamePolicy {
+    fn default() -> Self {
+        Self {
+            charset: Charset::default(),
+            replacement: default_replacement(),
+            max_length: default_max_length(),
+            percent_decode: default_percent_decode(),
+            normalization: Normalization::default(),
+            fallback: default_fallback(),
+        }
+    }
+}
+
+fn default_replacement() -> String {
+    "_".to_string()
+}
+
+fn default_max_length() -> usize {
+    255
+}
+
+fn default_percent_decode() -> bool {
+    true
+}
+
+fn default_fallback() -> String {
+    "download".to_string()
+}
+
+/// Characters allowed in filenames
+///
+/// Path separators, control characters and characters reserved on Windows (`<>:"|?*`) are never allowed.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Charset {
+    /// Any other unicode character
+    #[default]
+    Unicode,
+    /// Printable ASCII characters
+    Ascii,
+    /// POSIX portable filename characters (`A-Z`, `a-z`, `0-9`, `.`, `_` and `-`)
+    Portable,
+}
+
+impl Charset {
+    fn allows(&self, c: char) -> bool {
+        if c.is_control() || matches!(c, '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*') {
+            return false;
+        }
+
+        match self {
+            Charset::Unicode => true,
+            Charset::Ascii => c.is_ascii(),
+            Charset::Portable => c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'),
+        }
+    }
+}
+
+/// Unicode normalization forms
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    #[default]
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+    /// Keep filenames as they are
+    None,
+}
+
+/// Origin of a filename
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Source {
+    /// Filename from the Content-Disposition header
+    Header,
+    /// Last segment of the URL path
+    Url,
+}
+
+impl FilenamePolicy {
+    /// Sanitize a derived filename, falling back to the configured name when nothing usable is left
+    pub(crate) fn sanitize(
+        &self,
+        filename: Option<&str>,
+        source: Source,
+        url: &str,
+    ) -> Result<String, HandlerError> {
+        let filename = filename
+            .map(|filename| self.clean(filename, source))
+            .filter(|filename| !filename.is_empty() && filename != "." && filename != "..");
+
+        if let Some(filename) = filename {
+            return Ok(filename);
+        }
+
+        let fallback = self.fallback.replace(HASH_PLACEHOLDER, &url_hash(url));
+        let fallback = self.clean(&fallback, Source::Header);
+
+        if fallback.is_empty() || fallback == "." || fallback == ".." {
+            return Err(terminal(
+                "Failed to determine the filename and no fallback name is configured",
+            ));
+        }
+
+        Ok(fallback)
+    }
+
+    fn clean(&self, filename: &str, source: Source) -> String {
+        let filename = match source {
+            Source::Url if self.percent_decode => percent_decode_str(filename)
+                .decode_utf8_lossy()
+                .into_owned(),
+            _ => filename.to_string(),
+        };
+
+        let filename: String = match self.normalization {
+            Normalization::Nfc => filename.nfc().collect(),
+            Normalization::Nfd => filename.nfd().collect(),
+            Normalization::Nfkc => filename.nfkc().collect(),
+            Normalization::Nfkd => filename.nfkd().collect(),
+            Normalization::None => filename,
+        };
+
+        // The replacement must not reintroduce separators or other characters that are not allowed
+        let replacement: String = self
+            .replacement
+            .chars()
+            .filter(|&c| self.charset.allows(c))
+            .collect();
+
+        let mut filename =
+            filename
+                .chars()
+                .fold(String::with_capacity(filename.len()), |mut name, c| {
+                    if self.charset.allows(c) {
+                        name.push(c);
+                    } else {
+                        name.push_str(&replacement);
+                    }
+
+                    name
+                });
+
+        // Windows drops trailing dots and spaces, leading spaces are usually accidental
+        filename = filename
+            .trim_start_matches(' ')
+            .trim_end_matches(['.', ' '])
+            .to_string();
+
+        let stem_len = filename.find('.').unwrap_or(filename.len());
+
+        if RESERVED_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(&filename[..stem_len]))
+        {
+            // Dropping the disallowed characters cannot make a reserved name usable
+            let suffix = if replacement.is_empty() {
+                "_"
+            } else {
+                &replacement
+            };
+
+            filename.insert_str(stem_len, suffix);
+        }
+
+        truncate(filename, self.max_length)
+    }
+}
+
+/// Truncate a filename to a maximum length in bytes, preserving its extension when possible
+fn truncate(filename: String, max_length: usize) -> String {
+    if filename.len() <= max_length {
+        return filename;
+    }
+
+    let extension = filename
+        .rfind('.')
+        .filter(|&i| {
+            i > 0 && filename.len() - i <= MAX_EXTENSION_LEN && filename.len() - i < max_length
+        })
+        .map(|i| &filename[i..])
+        .unwrap_or("");
+
+    let mut end = max_length - extension.len();
+
+    while !filename.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{}", &filename[..end], extension)
+}
+
+fn url_hash(url: &str) -> String {
+    let mut hash = hex::encode(Sha256::digest(url.as_bytes()));
+    hash.truncate(HASH_LEN);
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test sanitizing filenames with the default policy
+    #[test]
+    fn test_sanitize() {
+        let policy = FilenamePolicy::default();
+
+        let cases = [
+            (Some("report.pdf"), Source::Header, "report.pdf"),
+            (Some("my%20file.txt"), Source::Url, "my file.txt"),
+            (Some("my%20file.txt"), Source::Header, "my%20file.txt"),
+            (Some("a%2Fb.txt"), Source::Url, "a_b.txt"),
+            (Some("../../etc/passwd"), Source::Header, ".._.._etc_passwd"),
+            (Some("evil\0name.txt"), Source::Header, "evil_name.txt"),
+            (Some("what?.txt"), Source::Header, "what_.txt"),
+            (Some("CON"), Source::Header, "CON_"),
+            (Some("nul.txt"), Source::Header, "nul_.txt"),
+            (Some("console.txt"), Source::Header, "console.txt"),
+            (Some("trailing. . "), Source::Header, "trailing"),
+            (Some("cafe\u{301}.txt"), Source::Header, "caf\u{e9}.txt"),
+            (Some(""), Source::Url, "download"),
+            (Some(".."), Source::Header, "download"),
+            (None, Source::Url, "download"),
+        ];
+
+        for (filename, source, expected) in cases {
+            assert_eq!(
+                policy
+                    .sanitize(filename, source, "https://example.com/")
+                    .unwrap(),
+                expected,
+                "filename: {:?}, source: {:?}",
+                filename,
+                source
+            );
+        }
+    }
+
+    /// Test the configurable parts of the policy
+    #[test]
+    fn test_sanitize_with_policy() {
+        let portable = FilenamePolicy {
+            charset: Charset::Portable,
+            replacement: "-".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            portable
+                .sanitize(Some("résumé final.pdf"), Source::Header, "")
+                .unwrap(),
+            "r-sum--final.pdf"
+        );
+
+        let ascii = FilenamePolicy {
+            charset: Charset::Ascii,
+            normalization: Normalization::Nfkc,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            ascii
+                .sanitize(Some("ﬁle①.txt"), Source::Header, "")
+                .unwrap(),
+            "file1.txt"
+        );
+
+        let hashed = FilenamePolicy {
+            fallback: "download-{hash}".to_string(),
+            ..Default::default()
+        };
+
+        let filename = hashed
+            .sanitize(None, Source::Url, "https://example.com/")
+            .unwrap();
+
+        assert_eq!(filename.len(), "download-".len() + HASH_LEN);
+        assert_eq!(
+            filename,
+            hashed
+                .sanitize(Some(""), Source::Url, "https://example.com/")
+                .unwrap()
+        );
+        assert_ne!(
+            filename,
+            hashed
+                .sanitize(None, Source::Url, "https://example.org/")
+                .unwrap()
+        );
+
+        let no_fallback = FilenamePolicy {
+            fallback: String::new(),
+            ..Default::default()
+        };
+
+        assert!(no_fallback.sanitize(None, Source::Url, "").is_err());
+    }
+
+    /// Test percent-decoding and unicode normalization of filenames
+    #[test]
+    fn test_sanitize_decoding() {
+        let policy = FilenamePolicy::default();
+        let sanitize = |policy: &FilenamePolicy, filename: &str, source: Source| {
+            policy.sanitize(Some(filename), source, "").unwrap()
+        };
+
+        assert_eq!(
+            sanitize(&policy, "r%C3%A9sum%C3%A9.pdf", Source::Url),
+            "r\u{e9}sum\u{e9}.pdf"
+        );
+        assert_eq!(
+            sanitize(&policy, "caf%65%CC%81.txt", Source::Url),
+            "caf\u{e9}.txt"
+        );
+        assert_eq!(sanitize(&policy, "%FF.txt", Source::Url), "\u{fffd}.txt");
+        assert_eq!(sanitize(&policy, "%2E%2E", Source::Url), "download");
+
+        let raw = FilenamePolicy {
+            percent_decode: false,
+            normalization: Normalization::Nfd,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            sanitize(&raw, "my%20file.txt", Source::Url),
+            "my%20file.txt"
+        );
+        assert_eq!(
+            sanitize(&raw, "caf\u{e9}.txt", Source::Header),
+            "cafe\u{301}.txt"
+        );
+    }
+
+    /// Test that long filenames are truncated at a character boundary, keeping the extension
+    #[test]
+    fn test_sanitize_truncation() {
+        let policy = FilenamePolicy {
+            max_length: 8,
+            ..Default::default()
+        };
+
+        let cases = [
+            ("\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}.txt", "\u{e9}\u{e9}.txt"),
+            ("a\u{e9}\u{e9}\u{e9}\u{e9}.txt", "a\u{e9}.txt"),
+            ("\u{1f600}\u{1f600}\u{1f600}", "\u{1f600}\u{1f600}"),
+        ];
+
+        for (filename, expected) in cases {
+            let sanitized = policy.sanitize(Some(filename), Source::Header, "").unwrap();
+
+            assert_eq!(sanitized, expected, "filename: {}", filename);
+            assert!(sanitized.len() <= 8);
+        }
+    }
+
+    /// Test that the charset rule and its replacement never produce an empty name, "." or ".."
+    #[test]
+    fn test_sanitize_replacement() {
+        let policy = |replacement: &str| FilenamePolicy {
+            charset: Charset::Portable,
+            replacement: replacement.to_string(),
+            fallback: "download-{hash}".to_string(),
+            ..Default::default()
+        };
+
+        let fallback = format!("download-{}", url_hash("https://example.com/"));
+
+        let cases = [
+            ("", "\u{65e5}\u{672c}", fallback.as_str()),
+            (".", "\u{65e5}\u{672c}", fallback.as_str()),
+            (".", "\u{65e5}", fallback.as_str()),
+            (".", "\u{65e5}\u{672c}\u{8a9e}", fallback.as_str()),
+            ("/", "a b", "ab"),
+            ("_/", "a b", "a_b"),
+            ("_", "\u{65e5}\u{672c}", "__"),
+            ("", "CON", "CON_"),
+        ];
+
+        for (replacement, filename, expected) in cases {
+            assert_eq!(
+                policy(replacement)
+                    .sanitize(Some(filename), Source::Header, "https://example.com/")
+                    .unwrap(),
+                expected,
+                "replacement: {:?}, filename: {}",
+                replacement,
+                filename
+            );
+        }
+
+        // The fallback is sanitized with the same rule
+        let unusable = FilenamePolicy {
+            fallback: "..".to_string(),
+            ..policy(".")
+        };
+
+        assert!(unusable.sanitize(None, Source::Url, "").is_err());
+
+        let nested = FilenamePolicy {
+            fallback: "dl/{hash}".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            nested
+                .sanitize(None, Source::Url, "https://example.com/")
+                .unwrap(),
+            format!("dl_{}", url_hash("https://example.com/"))
+        );
+    }
+
+    /// Test truncating long filenames
+    #[test]
+    fn test_truncate() {
+        let cases = [
+            ("short.txt", 255, "short.txt"),
+            ("abcdefghij.txt", 10, "abcdef.txt"),
+            ("abcdefghij", 5, "abcde"),
+            ("ééééé.txt", 8, "éé.txt"),
+            ("abc.verylongextensionname", 10, "abc.verylo"),
+        ];
+
+        for (filename, max_length, expected) in cases {
+            assert_eq!(
+                truncate(filename.to_string(), max_length),
+                expected,
+                "filename: {}, max length: {}",
+                filename,
+                max_length
+            );
+        }
+    }
+}
@@ -1,3 +1,16 @@
 pub mod common;
+mod compression;
+pub mod config;
+pub mod digest;
+pub mod egress;
+mod extract;
+pub mod filename;
+pub mod notify;
+pub mod progress;
+mod resume;
+mod segment;
+mod sidecar;
+#[cfg(test)]
+mod testing;
 pub mod with_store;
 pub mod without_store;
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+
+use futures::{FutureExt as _, future::BoxFuture};
+use hmac::{Hmac, Mac};
+use reqwest::header::{CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue};
+use restate_sdk::{
+    context::{ContextClient, RequestTarget},
+    errors::{HandlerError, TerminalError},
+    serde::Json,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use url::Url;
+
+use crate::{
+    common::{DownloadResponse, DownloadResult, http_error, terminal},
+    config::Config,
+    egress::EgressPolicy,
+};
+
+/// Header carrying the HMAC-SHA256 signature of the notification payload
+pub const SIGNATURE_HEADER: &str = "x-signature-256";
+
+/// Webhook notified when a download finishes
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NotifyOptions {
+    /// URL to POST the notification to
+    pub url: Url,
+    /// Headers to send with the notification
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    /// Arbitrary metadata included in the notification
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+/// Restate handler invoked with the result of a download
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CallbackOptions {
+    /// Name of the service or virtual object
+    #[schemars(length(min = 1))]
+    pub service: String,
+    /// Name of the handler
+    #[schemars(length(min = 1))]
+    pub handler: String,
+    /// Key of the virtual object (the target is a service without it)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Arbitrary metadata included in the notification
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+impl CallbackOptions {
+    fn target(&self) -> RequestTarget {
+        match &self.key {
+            Some(key) => RequestTarget::object(&self.service, key, &self.handler),
+            None => RequestTarget::service(&self.service, &self.handler),
+        }
+    }
+}
+
+/// Payload sent to the webhook or callback handler when a download finishes
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    /// Download ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// URL the file was downloaded from
+    pub url: Url,
+    /// Metadata from the notify options
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    /// Result of the download
+    #[serde(flatten)]
+    pub result: DownloadResult,
+}
+
+impl Notification {
+    fn new(
+        id: Option<String>,
+        url: Url,
+        metadata: HashMap<String, String>,
+        result: &Result<Json<DownloadResponse>, TerminalError>,
+    ) -> Self {
+        let result = match result {
+            Ok(response) => DownloadResult::Succeeded(response.0.clone()),
+            Err(err) => DownloadResult::Failed {
+                error: err.message().to_string(),
+            },
+        };
+
+        Self {
+            id,
+            url,
+            metadata,
+            result,
+        }
+    }
+}
+
+/// Sends the completion notification of a download to a webhook.
+///
+/// When the service is configured with a notify secret, the payload is signed with HMAC-SHA256
+/// and the signature is sent in the [`SIGNATURE_HEADER`] header as `sha256=<hex digest>`.
+pub(crate) struct Notifier {
+    client: reqwest::Client,
+    options: NotifyOptions,
+    id: Option<String>,
+    url: Url,
+    secret: Option<String>,
+    egress: EgressPolicy,
+}
+
+impl Notifier {
+    pub(crate) fn new(
+        client: reqwest::Client,
+        options: NotifyOptions,
+        id: Option<String>,
+        url: Url,
+        secret: Option<String>,
+        egress: EgressPolicy,
+    ) -> Self {
+        Self {
+            client,
+            options,
+            id,
+            url,
+            secret,
+            egress,
+        }
+    }
+
+    /// Build the notification for the result of the download
+    pub(crate) fn notification(
+        &self,
+        result: &Result<Json<DownloadResponse>, TerminalError>,
+    ) -> Notification {
+        Notification::new(
+            self.id.clone(),
+            self.url.clone(),
+            self.options.metadata.clone(),
+            result,
+        )
+    }
+
+    pub(crate) async fn send(&self, notification: &Notification) -> Result<(), HandlerError> {
+        self.egress.check_url(&self.options.url).map_err(terminal)?;
+
+        let body = serde_json::to_vec(notification).map_err(terminal)?;
+
+        let mut headers = HeaderMap::new();
+
+        for (key, value) in &self.options.headers {
+            let name = HeaderName::from_bytes(key.as_bytes()).map_err(terminal)?;
+            let value = HeaderValue::from_str(value).map_err(terminal)?;
+
+            headers.insert(name, value);
+        }
+
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        if let Some(secret) = &self.secret {
+            let signature = format!("sha256={}", sign(secret.as_bytes(), &body));
+
+            headers.insert(
+                SIGNATURE_HEADER,
+                HeaderValue::from_str(&signature).map_err(terminal)?,
+            );
+        }
+
+        self.client
+            .post(self.options.url.clone())
+            .headers(headers)
+            .body(body)
+            .send()
+            .await
+            .map_err(http_error)?
+            .error_for_status()
+            .map_err(http_error)?;
+
+        Ok(())
+    }
+}
+
+/// Invokes a Restate handler with the result of a download.
+///
+/// The handler is invoked with a one-way call, so the download does not wait for it to finish.
+pub(crate) struct Callback {
+    options: CallbackOptions,
+    id: Option<String>,
+    url: Url,
+}
+
+impl Callback {
+    pub(crate) fn new(options: CallbackOptions, id: Option<String>, url: Url) -> Self {
+        Self { options, id, url }
+    }
+
+    pub(crate) fn send<'ctx>(
+        &self,
+        ctx: &impl ContextClient<'ctx>,
+        result: &Result<Json<DownloadResponse>, TerminalError>,
+    ) {
+        let notification = Notification::new(
+            self.id.clone(),
+            self.url.clone(),
+            self.options.metadata.clone(),
+            result,
+        );
+
+        ctx.request::<_, ()>(self.options.target(), Json(notification))
+            .send();
+    }
+}
+
+/// Notifications sent when a download finishes (the callback handler and the webhook)
+pub(crate) struct Notifications {
+    notifier: Option<Notifier>,
+    callback: Option<Callback>,
+}
+
+impl Notifications {
+    pub(crate) fn new(
+        client: &reqwest::Client,
+        config: &Config,
+        id: Option<&str>,
+        url: &Url,
+        notify: Option<&NotifyOptions>,
+        callback: Option<&CallbackOptions>,
+    ) -> Self {
+        let id = id.map(String::from);
+
+        Self {
+            notifier: notify.map(|options| {
+                Notifier::new(
+                    client.clone(),
+                    options.clone(),
+                    id.clone(),
+                    url.clone(),
+                    config.notify_secret.clone(),
+                    config.egress.clone(),
+                )
+            }),
+            callback: callback.map(|options| Callback::new(options.clone(), id, url.clone())),
+        }
+    }
+
+    /// Invoke the callback handler with the result of the download and return the step notifying the webhook
+    ///
+    /// Run the step separately, so a failing webhook is retried without downloading the file again.
+    pub(crate) fn send<'a, 'ctx>(
+        &'a self,
+        ctx: &impl ContextClient<'ctx>,
+        result: &Result<Json<DownloadResponse>, TerminalError>,
+    ) -> Option<impl FnOnce() -> BoxFuture<'a, Result<(), HandlerError>> + Send + 'a> {
+        if let Some(callback) = &self.callback {
+            callback.send(ctx, result);
+        }
+
+        let notifier = self.notifier.as_ref()?;
+        let notification = notifier.notification(result);
+
+        Some(move || async move { notifier.send(&notification).await }.boxed())
+    }
+}
+
+/// Compute the hex encoded HMAC-SHA256 signature of a payload
+fn sign(secret: &[u8], payload: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test the signature against the well-known HMAC-SHA256 test vector
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign(b"key", b"The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    /// Test that the result of the download is flattened into the notification
+    #[test]
+    fn test_notification_payload() {
+        let notifier = Notifier::new(
+            reqwest::Client::new(),
+            NotifyOptions {
+                url: Url::parse("https://example.com/hook").unwrap(),
+                headers: HashMap::new(),
+                metadata: HashMap::from([("job".to_string(), "42".to_string())]),
+            },
+            Some("abc".to_string()),
+            Url::parse("https://example.com/file.pdf").unwrap(),
+            None,
+            EgressPolicy::default(),
+        );
+
+        let notification = notifier.notification(&Err(TerminalError::new("boom")));
+
+        assert_eq!(
+            serde_json::to_value(&notification).unwrap(),
+            serde_json::json!({
+                "id": "abc",
+                "url": "https://example.com/file.pdf",
+                "metadata": {"job": "42"},
+                "status": "failed",
+                "error": "boom",
+            })
+        );
+    }
+}
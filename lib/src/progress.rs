@@ -0,0 +1,548 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use reqwest::header::CONTENT_TYPE;
+use restate_sdk::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::common::DownloadResponse;
+
+#[allow(dead_code)]
+pub(crate) const PROGRESS_OBJECT_NAME: &str = match option_env!("RESTATE_PROGRESS_OBJECT_NAME") {
+    Some(name) => name,
+    None => "DownloadProgress",
+};
+
+/// Default minimum interval between progress reports of a running download
+pub(crate) const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Timeout of a single progress report sent to the Restate ingress
+const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const STATUS_KEY: &str = "status";
+
+/// Error code of a cancelled download (the same code Restate uses for cancelled invocations)
+pub(crate) const CANCELLED_CODE: u16 = 409;
+
+/// Error code of a cancellation request that cannot be delivered to the download
+const NOT_CANCELLABLE_CODE: u16 = 422;
+
+/// Error returned when a running download observes a cancellation request
+pub(crate) fn cancelled() -> HandlerError {
+    TerminalError::new_with_code(CANCELLED_CODE, "Download cancelled").into()
+}
+
+/// Progress of a download keyed by download ID
+#[restate_sdk::object(name = PROGRESS_OBJECT_NAME)]
+pub trait DownloadProgress {
+    async fn report(update: Json<ProgressUpdate>) -> Result<Json<ReportResponse>, HandlerError>;
+
+    /// Request cancellation of a running download
+    ///
+    /// The download observes the request the next time it reports progress,
+    /// so cancellation requires the Restate ingress URL to be configured for the service
+    /// (the request fails with a terminal error otherwise).
+    async fn cancel() -> Result<Json<DownloadStatus>, HandlerError>;
+
+    #[shared]
+    #[name = "getStatus"]
+    async fn get_status() -> Result<Json<DownloadStatus>, HandlerError>;
+}
+
+/// Event reported about a download
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(
+    tag = "event",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ProgressUpdate {
+    /// The download started (resets any previously recorded progress)
+    Started {
+        /// The download reports its progress, so it observes cancellation requests
+        #[serde(default)]
+        cancellable: bool,
+    },
+    /// Bytes were written to storage
+    Progress {
+        /// Number of bytes written so far
+        bytes_written: u64,
+        /// Total size of the file from the Content-Length header
+        #[serde(skip_serializing_if = "Option::is_none")]
+        total_bytes: Option<u64>,
+    },
+    /// The download finished successfully
+    Completed {
+        /// Size of the downloaded file
+        bytes_written: u64,
+    },
+    /// The download failed
+    Failed {
+        /// Reason of the failure
+        error: String,
+    },
+    /// The download was cancelled
+    Cancelled,
+}
+
+/// Response to a progress report
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportResponse {
+    /// Cancellation of the download was requested
+    pub cancelled: bool,
+}
+
+/// State of a download
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadState {
+    Running,
+    Cancelling,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl DownloadState {
+    fn is_final(&self) -> bool {
+        !matches!(self, DownloadState::Running | DownloadState::Cancelling)
+    }
+}
+
+/// Recorded status of a download
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadStatus {
+    /// State of the download
+    pub state: DownloadState,
+    /// Number of bytes written to storage
+    pub bytes_written: u64,
+    /// Total size of the file from the Content-Length header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_bytes: Option<u64>,
+    /// Average throughput in bytes per second
+    pub throughput: u64,
+    /// Time the download started
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub started_at: SystemTime,
+    /// Time the status was last updated
+    #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
+    pub updated_at: SystemTime,
+    /// Reason of the failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The download observes cancellation requests
+    #[serde(default)]
+    pub cancellable: bool,
+}
+
+impl DownloadStatus {
+    fn new(now: SystemTime) -> Self {
+        Self {
+            state: DownloadState::Running,
+            bytes_written: 0,
+            total_bytes: None,
+            throughput: 0,
+            started_at: now,
+            updated_at: now,
+            error: None,
+            cancellable: false,
+        }
+    }
+
+    /// Request cancellation of the download, returning whether the status changed
+    fn request_cancellation(&mut self, id: &str) -> Result<bool, HandlerError> {
+        if self.state != DownloadState::Running {
+            return Ok(false);
+        }
+
+        if !self.cancellable {
+            return Err(TerminalError::new_with_code(
+                NOT_CANCELLABLE_CODE,
+                format!(
+                    "Download {} cannot be cancelled: it does not report progress through the Restate ingress",
+                    id
+                ),
+            )
+            .into());
+        }
+
+        self.state = DownloadState::Cancelling;
+
+        Ok(true)
+    }
+
+    fn apply(&mut self, update: ProgressUpdate, now: SystemTime) {
+        match update {
+            ProgressUpdate::Started { cancellable } => {
+                *self = Self {
+                    cancellable,
+                    ..Self::new(now)
+                }
+            }
+            // Progress reports are sent outside the journal and may arrive after the download finished
+            ProgressUpdate::Progress { .. } if self.state.is_final() => return,
+            ProgressUpdate::Progress {
+                bytes_written,
+                total_bytes,
+            } => {
+                self.bytes_written = bytes_written;
+                self.total_bytes = total_bytes.or(self.total_bytes);
+            }
+            ProgressUpdate::Completed { bytes_written } => {
+                self.state = DownloadState::Completed;
+                self.bytes_written = bytes_written;
+                self.total_bytes = self.total_bytes.or(Some(bytes_written));
+            }
+            ProgressUpdate::Failed { error } => {
+                self.state = DownloadState::Failed;
+                self.error = Some(error);
+            }
+            ProgressUpdate::Cancelled => self.state = DownloadState::Cancelled,
+        }
+
+        self.updated_at = now;
+
+        let elapsed = now
+            .duration_since(self.started_at)
+            .unwrap_or_default()
+            .as_millis();
+
+        if let Some(throughput) = (self.bytes_written as u128 * 1000).checked_div(elapsed) {
+            self.throughput = throughput as u64;
+        }
+    }
+}
+
+pub struct DownloadProgressImpl;
+
+impl DownloadProgress for DownloadProgressImpl {
+    async fn report(
+        &self,
+        ctx: ObjectContext<'_>,
+        update: Json<ProgressUpdate>,
+    ) -> Result<Json<ReportResponse>, HandlerError> {
+        let now = ctx.run(async || Ok(now_millis())).await?;
+        let now = UNIX_EPOCH + Duration::from_millis(now);
+
+        let mut status = ctx
+            .get::<Json<DownloadStatus>>(STATUS_KEY)
+            .await?
+            .map(Json::into_inner)
+            .unwrap_or_else(|| DownloadStatus::new(now));
+
+        status.apply(update.into_inner(), now);
+
+        let cancelled = status.state == DownloadState::Cancelling;
+
+        ctx.set(STATUS_KEY, Json(status));
+
+        Ok(Json(ReportResponse { cancelled }))
+    }
+
+    async fn cancel(&self, ctx: ObjectContext<'_>) -> Result<Json<DownloadStatus>, HandlerError> {
+        let mut status = ctx
+            .get::<Json<DownloadStatus>>(STATUS_KEY)
+            .await?
+            .map(Json::into_inner)
+            .ok_or_else(|| unknown_download(ctx.key()))?;
+
+        if status.request_cancellation(ctx.key())? {
+            ctx.set(STATUS_KEY, Json(status.clone()));
+        }
+
+        Ok(Json(status))
+    }
+
+    async fn get_status(
+        &self,
+        ctx: SharedObjectContext<'_>,
+    ) -> Result<Json<DownloadStatus>, HandlerError> {
+        ctx.get::<Json<DownloadStatus>>(STATUS_KEY)
+            .await?
+            .ok_or_else(|| unknown_download(ctx.key()))
+    }
+}
+
+fn unknown_download(id: &str) -> HandlerError {
+    TerminalError::new_with_code(404, format!("Unknown download: {}", id)).into()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Record the start of a download in its progress object
+pub(crate) fn report_started<'ctx>(
+    ctx: &impl ContextClient<'ctx>,
+    id: Option<&str>,
+    cancellable: bool,
+) {
+    if let Some(id) = id {
+        ctx.object_client::<DownloadProgressClient>(id)
+            .report(Json(ProgressUpdate::Started { cancellable }))
+            .send();
+    }
+}
+
+/// Record the outcome of a download in its progress object
+pub(crate) fn report_finished<'ctx>(
+    ctx: &impl ContextClient<'ctx>,
+    id: Option<&str>,
+    result: &Result<Json<DownloadResponse>, TerminalError>,
+) {
+    let Some(id) = id else {
+        return;
+    };
+
+    let update = match result {
+        Ok(response) => ProgressUpdate::Completed {
+            bytes_written: response.0.size,
+        },
+        Err(err) if err.code() == CANCELLED_CODE => ProgressUpdate::Cancelled,
+        Err(err) => ProgressUpdate::Failed {
+            error: err.message().to_string(),
+        },
+    };
+
+    ctx.object_client::<DownloadProgressClient>(id)
+        .report(Json(update))
+        .send();
+}
+
+/// Periodically reports the progress of a running download and checks whether it was cancelled.
+///
+/// Reports are sent from inside the download (which runs as a side effect and has no access to the Restate context),
+/// so they go through the Restate ingress.
+/// Reporting is best-effort: a failed report is logged and never fails the download.
+pub(crate) struct ProgressReporter {
+    client: reqwest::Client,
+    url: Url,
+    interval: Duration,
+    total_bytes: Option<u64>,
+    last_report: Option<Instant>,
+}
+
+impl ProgressReporter {
+    pub(crate) fn new(
+        client: reqwest::Client,
+        ingress_url: &Url,
+        id: &str,
+        interval: Duration,
+        total_bytes: Option<u64>,
+    ) -> Result<Self> {
+        Ok(Self {
+            client,
+            url: report_url(ingress_url, id)?,
+            interval,
+            total_bytes,
+            last_report: None,
+        })
+    }
+
+    /// Report the number of bytes written if the reporting interval elapsed since the last report.
+    ///
+    /// Returns whether cancellation of the download was requested.
+    pub(crate) async fn report(&mut self, bytes_written: u64) -> bool {
+        if self
+            .last_report
+            .is_some_and(|last| last.elapsed() < self.interval)
+        {
+            return false;
+        }
+
+        self.last_report = Some(Instant::now());
+
+        let update = ProgressUpdate::Progress {
+            bytes_written,
+            total_bytes: self.total_bytes,
+        };
+
+        match self.send(&update).await {
+            Ok(response) => response.cancelled,
+            Err(err) => {
+                tracing::warn!(error = %err, "Failed to report download progress");
+
+                false
+            }
+        }
+    }
+
+    async fn send(&self, update: &ProgressUpdate) -> Result<ReportResponse> {
+        let body = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(update)?)
+            .timeout(REPORT_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+/// Build the ingress URL of the report handler of a progress object
+fn report_url(ingress_url: &Url, id: &str) -> Result<Url> {
+    let mut url = ingress_url.clone();
+
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid Restate ingress URL: {}", ingress_url))?
+        .pop_if_empty()
+        .extend([PROGRESS_OBJECT_NAME, id, "report"]);
+
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::terminal_error;
+
+    /// Test that progress updates are applied to the status and late progress reports are ignored
+    #[test]
+    fn test_status_apply() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut status = DownloadStatus::new(start);
+
+        status.apply(
+            ProgressUpdate::Progress {
+                bytes_written: 2_000,
+                total_bytes: Some(4_000),
+            },
+            start + Duration::from_secs(2),
+        );
+
+        assert_eq!(status.state, DownloadState::Running);
+        assert_eq!(status.bytes_written, 2_000);
+        assert_eq!(status.total_bytes, Some(4_000));
+        assert_eq!(status.throughput, 1_000);
+
+        status.apply(
+            ProgressUpdate::Completed {
+                bytes_written: 4_000,
+            },
+            start + Duration::from_secs(4),
+        );
+
+        status.apply(
+            ProgressUpdate::Progress {
+                bytes_written: 3_000,
+                total_bytes: Some(4_000),
+            },
+            start + Duration::from_secs(5),
+        );
+
+        assert_eq!(status.state, DownloadState::Completed);
+        assert_eq!(status.bytes_written, 4_000);
+        assert_eq!(status.updated_at, start + Duration::from_secs(4));
+
+        status.apply(
+            ProgressUpdate::Started { cancellable: true },
+            start + Duration::from_secs(10),
+        );
+
+        assert_eq!(status.state, DownloadState::Running);
+        assert_eq!(status.bytes_written, 0);
+        assert_eq!(status.started_at, start + Duration::from_secs(10));
+        assert!(status.cancellable);
+    }
+
+    /// Test that cancellation is only requested from running downloads that observe it
+    #[test]
+    fn test_request_cancellation() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut status = DownloadStatus::new(start);
+
+        status.apply(ProgressUpdate::Started { cancellable: false }, start);
+
+        let err = status.request_cancellation("abc").unwrap_err();
+
+        assert!(
+            terminal_error(&err).is_some_and(|(code, message)| code == NOT_CANCELLABLE_CODE
+                && message.starts_with("Download abc cannot be cancelled"))
+        );
+        assert_eq!(status.state, DownloadState::Running);
+
+        status.apply(ProgressUpdate::Started { cancellable: true }, start);
+
+        assert!(status.request_cancellation("abc").unwrap());
+        assert_eq!(status.state, DownloadState::Cancelling);
+        assert!(!status.request_cancellation("abc").unwrap());
+
+        status.apply(ProgressUpdate::Cancelled, start);
+
+        assert!(!status.request_cancellation("abc").unwrap());
+        assert_eq!(status.state, DownloadState::Cancelled);
+    }
+
+    /// Test that progress updates without the cancellable flag are still accepted
+    #[test]
+    fn test_started_without_cancellable() {
+        let update: ProgressUpdate = serde_json::from_str(r#"{"event":"started"}"#).unwrap();
+
+        assert_eq!(update, ProgressUpdate::Started { cancellable: false });
+    }
+
+    /// Test that a download requested to be cancelled keeps reporting progress until it is cancelled
+    #[test]
+    fn test_status_apply_cancellation() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_000);
+        let mut status = DownloadStatus::new(start);
+        status.state = DownloadState::Cancelling;
+
+        status.apply(
+            ProgressUpdate::Progress {
+                bytes_written: 1_000,
+                total_bytes: None,
+            },
+            start + Duration::from_secs(1),
+        );
+
+        assert_eq!(status.state, DownloadState::Cancelling);
+        assert_eq!(status.bytes_written, 1_000);
+
+        status.apply(ProgressUpdate::Cancelled, start + Duration::from_secs(2));
+
+        assert_eq!(status.state, DownloadState::Cancelled);
+    }
+
+    /// Test that the report URL is built from the ingress URL with the download ID as a single path segment
+    #[test]
+    fn test_report_url() {
+        let test_cases = vec![
+            (
+                "http://localhost:8080",
+                "abc",
+                "http://localhost:8080/DownloadProgress/abc/report",
+            ),
+            (
+                "http://restate:8080/ingress/",
+                "a/b c",
+                "http://restate:8080/ingress/DownloadProgress/a%2Fb%20c/report",
+            ),
+        ];
+
+        for (ingress_url, id, expected) in test_cases {
+            let url = report_url(&Url::parse(ingress_url).unwrap(), id).unwrap();
+
+            assert_eq!(
+                url.as_str(),
+                expected,
+                "Failed for ingress URL '{}'",
+                ingress_url
+            );
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use anyhow::{Context as _, Result, bail};
+use bytes::Bytes;
+use futures::{StreamExt as _, stream::BoxStream};
+use reqwest::{
+    Response, StatusCode,
+    header::{
+        ACCEPT_RANGES, CONTENT_RANGE, ETAG, HeaderMap, HeaderValue, IF_RANGE, LAST_MODIFIED, RANGE,
+    },
+};
+use url::Url;
+
+use crate::common::{RequestOptions, create_request};
+
+/// Default number of attempts to resume an interrupted download
+pub(crate) const DEFAULT_MAX_RESUME_ATTEMPTS: u32 = 3;
+
+struct State {
+    client: reqwest::Client,
+    url: Url,
+    options: Option<RequestOptions>,
+    validator: Option<HeaderValue>,
+    offset: u64,
+    attempts: u32,
+    max_attempts: u32,
+    stream: BoxStream<'static, reqwest::Result<Bytes>>,
+    failed: bool,
+}
+
+/// Turn a response into a byte stream that resumes from the last received byte when the
+/// connection fails mid-stream.
+///
+/// Resuming issues a `Range: bytes=N-` request guarded by `If-Range`, so it is only attempted
+/// when the server advertised byte range support and returned a validator (strong ETag or Last-Modified).
+/// If the server answers the range request with anything other than the expected partial content,
+/// the stream fails and the download has to be restarted from scratch.
+/// The offset only lives in the stream, so a download is never resumed across retries of the step reading it.
+pub(crate) fn resumable_stream(
+    client: reqwest::Client,
+    response: Response,
+    options: Option<RequestOptions>,
+    max_attempts: u32,
+) -> BoxStream<'static, Result<Bytes>> {
+    let validator = accepts_ranges(response.headers())
+        .then(|| validator(response.headers()))
+        .flatten();
+
+    let state = State {
+        client,
+        url: response.url().clone(),
+        options,
+        validator,
+        offset: 0,
+        attempts: 0,
+        max_attempts,
+        stream: response.bytes_stream().boxed(),
+        failed: false,
+    };
+
+    futures::stream::unfold(state, |mut state| async move {
+        if state.failed {
+            return None;
+        }
+
+        loop {
+            let err = match state.stream.next().await {
+                Some(Ok(chunk)) => {
+                    state.offset += chunk.len() as u64;
+
+                    return Some((Ok(chunk), state));
+                }
+                Some(Err(e)) => anyhow::Error::new(e),
+                None => return None,
+            };
+
+            let Some(validator) = state.validator.clone() else {
+                state.failed = true;
+
+                return Some((Err(err), state));
+            };
+
+            if state.attempts >= state.max_attempts {
+                state.failed = true;
+
+                return Some((
+                    Err(err.context(format!(
+                        "Giving up after {} resume attempts",
+                        state.attempts
+                    ))),
+                    state,
+                ));
+            }
+
+            state.attempts += 1;
+
+            let resumed = resume(
+                &state.client,
+                &state.url,
+                state.options.clone(),
+                state.offset,
+                validator,
+            )
+            .await;
+
+            match resumed {
+                Ok(stream) => state.stream = stream,
+                Err(resume_err) => {
+                    state.failed = true;
+
+                    return Some((Err(resume_err.context(err.to_string())), state));
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+async fn resume(
+    client: &reqwest::Client,
+    url: &Url,
+    options: Option<RequestOptions>,
+    offset: u64,
+    validator: HeaderValue,
+) -> Result<BoxStream<'static, reqwest::Result<Bytes>>> {
+    let response = create_request(client, url.clone(), options)?
+        .header(RANGE, format!("bytes={}-", offset))
+        .header(IF_RANGE, validator)
+        .send()
+        .await
+        .context("Failed to send range request to resume download")?;
+
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        bail!(
+            "Server did not honour the range request to resume download (status: {})",
+            response.status()
+        );
+    }
+
+    if range_start(response.headers()) != Some(offset) {
+        bail!("Server returned an unexpected range to resume download");
+    }
+
+    Ok(response.bytes_stream().boxed())
+}
+
+pub(crate) fn accepts_ranges(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT_RANGES)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("bytes"))
+}
+
+/// Select a validator usable in an `If-Range` header (weak ETags are not allowed there)
+pub(crate) fn validator(headers: &HeaderMap) -> Option<HeaderValue> {
+    headers
+        .get(ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(LAST_MODIFIED))
+        .cloned()
+}
+
+/// Parse the first byte position from a `Content-Range: bytes start-end/total` header
+pub(crate) fn range_start(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split_once('-')?
+        .0
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::testing::{self, Reply, Request};
+
+    const CONTENT: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+
+    /// Serve the content, dropping the connection after the first 10 bytes unless a range is requested
+    fn serve_interrupted(request: &Request) -> Reply {
+        let Some((start, _)) = request.range() else {
+            let mut reply = Reply::new(200, CONTENT)
+                .header("accept-ranges", "bytes")
+                .header("etag", "\"v1\"");
+            reply.sent = Some(10);
+
+            return reply;
+        };
+
+        if request.headers.get("if-range").map(String::as_str) != Some("\"v1\"") {
+            return Reply::new(200, CONTENT);
+        }
+
+        Reply::new(206, &CONTENT[start as usize..]).header(
+            "content-range",
+            format!("bytes {}-{}/{}", start, CONTENT.len() - 1, CONTENT.len()),
+        )
+    }
+
+    /// Download the content served by a handler, resuming at most twice
+    async fn download(
+        handler: impl Fn(&Request) -> Reply + Send + Sync + 'static,
+    ) -> Result<Vec<u8>> {
+        let client = reqwest::Client::new();
+        let response = client.get(testing::serve(handler).await).send().await?;
+
+        let chunks: Vec<Bytes> = resumable_stream(client, response, None, 2)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_>>()?;
+
+        Ok(chunks.concat())
+    }
+
+    /// Test that a download dropped mid-body is resumed from the last received byte
+    #[tokio::test]
+    async fn test_resumable_stream() {
+        let ranges = Arc::new(AtomicUsize::new(0));
+        let handler_ranges = ranges.clone();
+
+        let content = download(move |request| {
+            if let Some((start, _)) = request.range() {
+                assert_eq!(start, 10);
+                handler_ranges.fetch_add(1, Ordering::SeqCst);
+            }
+
+            serve_interrupted(request)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(content, CONTENT);
+        assert_eq!(ranges.load(Ordering::SeqCst), 1);
+    }
+
+    /// Test that the download fails when the source changed or answers with an unexpected range
+    #[tokio::test]
+    async fn test_resumable_stream_mismatch() {
+        // The source changed, so the If-Range validator no longer matches and the server sends the whole file
+        let changed = download(|request| match request.range() {
+            Some(_) => Reply::new(200, b"ABCDEFGHIJKLMNOPQRSTUVWXYZ").header("etag", "\"v2\""),
+            None => serve_interrupted(request),
+        })
+        .await;
+
+        assert!(format!("{:#}", changed.unwrap_err()).contains("did not honour the range request"));
+
+        let unexpected = download(|request| match request.range() {
+            Some(_) => Reply::new(206, &CONTENT[5..]).header("content-range", "bytes 5-25/26"),
+            None => serve_interrupted(request),
+        })
+        .await;
+
+        assert!(format!("{:#}", unexpected.unwrap_err()).contains("unexpected range"));
+    }
+
+    /// Test that a download without a validator is not resumed
+    #[tokio::test]
+    async fn test_resumable_stream_without_validator() {
+        let ranges = Arc::new(AtomicUsize::new(0));
+        let handler_ranges = ranges.clone();
+
+        let result = download(move |request| {
+            if request.range().is_some() {
+                handler_ranges.fetch_add(1, Ordering::SeqCst);
+            }
+
+            let mut reply = Reply::new(200, CONTENT).header("accept-ranges", "bytes");
+            reply.sent = Some(10);
+
+            reply
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(ranges.load(Ordering::SeqCst), 0);
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.parse().unwrap(), HeaderValue::from_static(v)))
+            .collect()
+    }
+
+    /// Test that strong ETags are preferred and weak ETags fall back to Last-Modified
+    #[test]
+    fn test_validator_selection() {
+        let test_cases = vec![
+            (
+                headers(&[
+                    ("etag", "\"abc\""),
+                    ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+                ]),
+                Some("\"abc\""),
+            ),
+            (
+                headers(&[
+                    ("etag", "W/\"abc\""),
+                    ("last-modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+                ]),
+                Some("Wed, 21 Oct 2015 07:28:00 GMT"),
+            ),
+            (headers(&[("etag", "W/\"abc\"")]), None),
+            (headers(&[]), None),
+        ];
+
+        for (headers, expected) in test_cases {
+            assert_eq!(
+                validator(&headers).as_ref().map(|v| v.to_str().unwrap()),
+                expected,
+                "Failed for headers: {:?}",
+                headers
+            );
+        }
+    }
+
+    /// Test parsing of the start position from Content-Range headers
+    #[test]
+    fn test_range_start() {
+        let test_cases = vec![
+            ("bytes 100-199/200", Some(100)),
+            ("bytes 0-0/*", Some(0)),
+            ("bytes */200", None),
+            ("items 100-199/200", None),
+        ];
+
+        for (content_range, expected) in test_cases {
+            let headers = headers(&[("content-range", content_range)]);
+
+            assert_eq!(
+                range_start(&headers),
+                expected,
+                "Failed for content-range: {}",
+                content_range
+            );
+        }
+    }
+
+    /// Test that only an explicit "bytes" Accept-Ranges header enables resuming
+    #[test]
+    fn test_accepts_ranges() {
+        assert!(accepts_ranges(&headers(&[("accept-ranges", "bytes")])));
+        assert!(!accepts_ranges(&headers(&[("accept-ranges", "none")])));
+        assert!(!accepts_ranges(&headers(&[])));
+    }
+}
@@ -0,0 +1,329 @@
+use anyhow::{Context as _, Result, bail};
+use bytes::Bytes;
+use futures::{StreamExt as _, stream::BoxStream};
+use reqwest::{
+    Response, StatusCode,
+    header::{HeaderValue, IF_RANGE, RANGE},
+};
+use url::Url;
+
+use crate::{
+    common::{RequestOptions, create_request},
+    config::Config,
+    resume::{accepts_ranges, range_start, validator},
+};
+
+/// Default size of a segment in parallel segmented downloads
+const DEFAULT_SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Default upper bound of the segment size
+const DEFAULT_MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Default upper bound of the number of segments downloaded concurrently
+const DEFAULT_MAX_CONCURRENCY: usize = 16;
+
+/// Parameters of a parallel segmented download
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Segmentation {
+    /// Size of a segment in bytes
+    pub size: u64,
+    /// Number of segments downloaded concurrently
+    pub concurrency: usize,
+}
+
+impl Segmentation {
+    /// Determine the segmentation of a download, capping the requested values at the service maximums
+    /// (up to `size × concurrency` bytes are buffered in memory)
+    pub(crate) fn new(request: Option<&RequestOptions>, config: &Config) -> Self {
+        let size = request
+            .and_then(|r| r.segment_size)
+            .or(config.segment_size)
+            .unwrap_or(DEFAULT_SEGMENT_SIZE)
+            .min(config.max_segment_size.unwrap_or(DEFAULT_MAX_SEGMENT_SIZE));
+
+        let concurrency = request
+            .and_then(|r| r.concurrency)
+            .or(config.concurrency)
+            .unwrap_or(1)
+            .min(config.max_concurrency.unwrap_or(DEFAULT_MAX_CONCURRENCY));
+
+        Self { size, concurrency }
+    }
+
+    /// Check whether a response can be downloaded in segments
+    ///
+    /// The server has to advertise byte range support, return a validator for `If-Range`
+    /// and the content has to be larger than a single segment.
+    pub(crate) fn applies_to(&self, response: &Response) -> bool {
+        self.concurrency > 1
+            && self.size > 0
+            && response.content_length().is_some_and(|l| l > self.size)
+            && accepts_ranges(response.headers())
+            && validator(response.headers()).is_some()
+    }
+}
+
+/// Download the content of a response as concurrently fetched byte ranges.
+///
+/// Segments are fetched with the same request options as the original request and yielded in order,
+/// so at most `concurrency` segments are buffered in memory at a time.
+/// The caller is expected to check [`Segmentation::applies_to`] first.
+pub(crate) fn segmented_stream(
+    client: reqwest::Client,
+    response: &Response,
+    options: Option<RequestOptions>,
+    segmentation: Segmentation,
+    max_attempts: u32,
+) -> BoxStream<'static, Result<Bytes>> {
+    let url = response.url().clone();
+    let length = response.content_length().unwrap_or_default();
+    let validator = validator(response.headers());
+
+    futures::stream::iter(segments(length, segmentation.size))
+        .map(move |(start, end)| {
+            fetch_segment(
+                client.clone(),
+                url.clone(),
+                options.clone(),
+                validator.clone(),
+                (start, end),
+                max_attempts,
+            )
+        })
+        .buffered(segmentation.concurrency)
+        .boxed()
+}
+
+/// Split content of the given length into inclusive byte ranges
+fn segments(length: u64, size: u64) -> impl Iterator<Item = (u64, u64)> {
+    (0..length)
+        .step_by(size as usize)
+        .map(move |start| (start, (start + size).min(length) - 1))
+}
+
+async fn fetch_segment(
+    client: reqwest::Client,
+    url: Url,
+    options: Option<RequestOptions>,
+    validator: Option<HeaderValue>,
+    range: (u64, u64),
+    max_attempts: u32,
+) -> Result<Bytes> {
+    let mut attempts = 0;
+
+    loop {
+        match try_fetch_segment(&client, &url, options.clone(), validator.clone(), range).await {
+            Ok(bytes) => return Ok(bytes),
+            Err(_) if attempts < max_attempts => attempts += 1,
+            Err(e) => {
+                return Err(e.context(format!(
+                    "Failed to download segment {}-{}",
+                    range.0, range.1
+                )));
+            }
+        }
+    }
+}
+
+async fn try_fetch_segment(
+    client: &reqwest::Client,
+    url: &Url,
+    options: Option<RequestOptions>,
+    validator: Option<HeaderValue>,
+    (start, end): (u64, u64),
+) -> Result<Bytes> {
+    let mut request =
+        create_request(client, url.clone(), options)?.header(RANGE, format!("bytes={start}-{end}"));
+
+    if let Some(validator) = validator {
+        request = request.header(IF_RANGE, validator);
+    }
+
+    let response = request
+        .send()
+        .await
+        .context("Failed to send range request")?;
+
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        bail!(
+            "Server did not honour the range request (status: {})",
+            response.status()
+        );
+    }
+
+    if range_start(response.headers()) != Some(start) {
+        bail!("Server returned an unexpected range");
+    }
+
+    let bytes = response
+        .bytes()
+        .await
+        .context("Failed to read segment from HTTP response")?;
+
+    if bytes.len() as u64 != end - start + 1 {
+        bail!(
+            "Segment size mismatch: expected {} bytes, got {}",
+            end - start + 1,
+            bytes.len()
+        );
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::testing::{self, Reply, Request};
+
+    const CONTENT: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+
+    /// Serve the content in byte ranges, answering later ranges faster than earlier ones
+    fn serve_range(request: &Request) -> Reply {
+        let Some((start, end)) = request.range() else {
+            return Reply::new(200, CONTENT)
+                .header("accept-ranges", "bytes")
+                .header("etag", "\"v1\"");
+        };
+
+        let end = end.unwrap_or(CONTENT.len() as u64 - 1);
+
+        if request.headers.get("if-range").map(String::as_str) != Some("\"v1\"") {
+            return Reply::new(200, CONTENT);
+        }
+
+        let mut reply = Reply::new(206, &CONTENT[start as usize..=end as usize]).header(
+            "content-range",
+            format!("bytes {}-{}/{}", start, end, CONTENT.len()),
+        );
+        reply.delay = Duration::from_millis(CONTENT.len() as u64 - start);
+
+        reply
+    }
+
+    /// Download the content served by a handler in segments of 5 bytes, 3 at a time
+    async fn download(
+        handler: impl Fn(&Request) -> Reply + Send + Sync + 'static,
+    ) -> Result<Vec<u8>> {
+        let client = reqwest::Client::new();
+        let response = client.get(testing::serve(handler).await).send().await?;
+        let segmentation = Segmentation {
+            size: 5,
+            concurrency: 3,
+        };
+
+        assert!(segmentation.applies_to(&response));
+
+        let chunks: Vec<Bytes> = segmented_stream(client, &response, None, segmentation, 1)
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_>>()?;
+
+        Ok(chunks.concat())
+    }
+
+    /// Test that segments answered out of order are reassembled in order
+    #[tokio::test]
+    async fn test_segmented_stream() {
+        assert_eq!(download(serve_range).await.unwrap(), CONTENT);
+    }
+
+    /// Test that a failed range is retried
+    #[tokio::test]
+    async fn test_segmented_stream_retry() {
+        let failures = Arc::new(AtomicUsize::new(0));
+        let handler_failures = failures.clone();
+
+        let content = download(move |request| match request.range() {
+            Some((10, _)) if handler_failures.fetch_add(1, Ordering::SeqCst) == 0 => {
+                Reply::new(500, b"")
+            }
+            _ => serve_range(request),
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(content, CONTENT);
+        assert_eq!(failures.load(Ordering::SeqCst), 2);
+    }
+
+    /// Test that short ranges and servers ignoring the range fail the download once the retries are exhausted
+    #[tokio::test]
+    async fn test_segmented_stream_failure() {
+        let short = download(|request| match request.range() {
+            Some((5, _)) => Reply::new(206, b"fgh").header("content-range", "bytes 5-7/26"),
+            _ => serve_range(request),
+        })
+        .await;
+
+        assert!(short.is_err());
+
+        let ignored = download(|request| match request.range() {
+            Some((15, _)) => Reply::new(200, CONTENT),
+            _ => serve_range(request),
+        })
+        .await;
+
+        assert!(ignored.is_err());
+    }
+
+    /// Test that the requested segment size and concurrency are capped at the service maximums
+    #[test]
+    fn test_segmentation() {
+        let request = RequestOptions {
+            headers: Default::default(),
+            timeout: None,
+            max_resume_attempts: None,
+            segment_size: Some(u64::MAX),
+            concurrency: Some(usize::MAX),
+            max_size: None,
+        };
+
+        let segmentation = Segmentation::new(Some(&request), &Config::default());
+
+        assert_eq!(segmentation.size, DEFAULT_MAX_SEGMENT_SIZE);
+        assert_eq!(segmentation.concurrency, DEFAULT_MAX_CONCURRENCY);
+
+        let config = Config {
+            concurrency: Some(4),
+            max_segment_size: Some(1024),
+            max_concurrency: Some(2),
+            ..Default::default()
+        };
+
+        let segmentation = Segmentation::new(None, &config);
+
+        assert_eq!(segmentation.size, 1024);
+        assert_eq!(segmentation.concurrency, 2);
+    }
+
+    /// Test that content is split into contiguous inclusive ranges covering every byte
+    #[test]
+    fn test_segments() {
+        let test_cases = vec![
+            (10, 5, vec![(0, 4), (5, 9)]),
+            (11, 5, vec![(0, 4), (5, 9), (10, 10)]),
+            (4, 5, vec![(0, 3)]),
+            (0, 5, vec![]),
+        ];
+
+        for (length, size, expected) in test_cases {
+            assert_eq!(
+                segments(length, size).collect::<Vec<_>>(),
+                expected,
+                "Failed for length {} and segment size {}",
+                length,
+                size
+            );
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use opendal::{
+    Buffer, Metadata, Operator, Result,
+    raw::{
+        Access, Layer, LayeredAccess, OpCopy, OpDelete, OpList, OpRead, OpRename, OpStat, OpWrite,
+        RpCopy, RpDelete, RpList, RpRead, RpRename, RpStat, RpWrite,
+        oio::{self, Delete as _, Read as _, Write as _},
+    },
+    services::Memory,
+};
+use restate_sdk::errors::HandlerError;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::TcpListener,
+};
+use url::Url;
+
+/// Message of a terminal error with the default code (`None` for errors that are retried)
+pub(crate) fn terminal_message(err: &HandlerError) -> Option<String> {
+    terminal_error(err)
+        .filter(|(code, _)| *code == 500)
+        .map(|(_, message)| message)
+}
+
+/// Code and message of a terminal error (`None` for errors that are retried)
+pub(crate) fn terminal_error(err: &HandlerError) -> Option<(u16, String)> {
+    let err: &dyn std::error::Error = err.as_ref();
+    let (code, message) = err
+        .to_string()
+        .strip_prefix("Terminal error [")?
+        .split_once("]: ")
+        .map(|(code, message)| (code.parse().ok(), message.to_string()))?;
+
+    Some((code?, message))
+}
+
+/// In-memory operator that supports user metadata, copy and rename like the object stores the service writes to
+pub(crate) fn operator() -> Operator {
+    Operator::new(Memory::default())
+        .unwrap()
+        .layer(ObjectStoreLayer::default())
+        .finish()
+}
+
+/// Keeps the user metadata of written files and copies files by reading and writing them
+#[derive(Debug, Default)]
+struct ObjectStoreLayer {
+    metadata: Arc<Mutex<HashMap<String, HashMap<String, String>>>>,
+}
+
+impl<A: Access> Layer<A> for ObjectStoreLayer {
+    type LayeredAccess = ObjectStoreAccessor<A>;
+
+    fn layer(&self, inner: A) -> Self::LayeredAccess {
+        inner.info().update_full_capability(|mut capability| {
+            capability.write_with_user_metadata = true;
+            capability.copy = true;
+            capability.rename = true;
+            capability
+        });
+
+        ObjectStoreAccessor {
+            inner,
+            metadata: self.metadata.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ObjectStoreAccessor<A> {
+    inner: A,
+    metadata: Arc<Mutex<HashMap<String, HashMap<String, String>>>>,
+}
+
+impl<A: Access> LayeredAccess for ObjectStoreAccessor<A> {
+    type Inner = A;
+    type Reader = A::Reader;
+    type Writer = ObjectStoreWriter<A::Writer>;
+    type Lister = A::Lister;
+    type Deleter = A::Deleter;
+
+    fn inner(&self) -> &Self::Inner {
+        &self.inner
+    }
+
+    async fn read(&self, path: &str, args: OpRead) -> Result<(RpRead, Self::Reader)> {
+        self.inner.read(path, args).await
+    }
+
+    async fn write(&self, path: &str, args: OpWrite) -> Result<(RpWrite, Self::Writer)> {
+        let user_metadata = args.user_metadata().cloned();
+        let (rp, inner) = self.inner.write(path, args).await?;
+
+        Ok((
+            rp,
+            ObjectStoreWriter {
+                inner,
+                path: path.to_string(),
+                user_metadata,
+                metadata: self.metadata.clone(),
+            },
+        ))
+    }
+
+    async fn copy(&self, from: &str, to: &str, _: OpCopy) -> Result<RpCopy> {
+        let (_, mut reader) = self.inner.read(from, OpRead::default()).await?;
+        let content = reader.read_all().await?;
+
+        let (_, mut writer) = self.inner.write(to, OpWrite::default()).await?;
+        writer.write(content).await?;
+        writer.close().await?;
+
+        let mut metadata = self.metadata.lock().unwrap();
+
+        match metadata.get(from).cloned() {
+            Some(user_metadata) => metadata.insert(to.to_string(), user_metadata),
+            None => metadata.remove(to),
+        };
+
+        Ok(RpCopy::default())
+    }
+
+    async fn rename(&self, from: &str, to: &str, _: OpRename) -> Result<RpRename> {
+        LayeredAccess::copy(self, from, to, OpCopy::default()).await?;
+
+        let (_, mut deleter) = self.inner.delete().await?;
+        deleter.delete(from, OpDelete::default())?;
+        deleter.flush().await?;
+
+        self.metadata.lock().unwrap().remove(from);
+
+        Ok(RpRename::default())
+    }
+
+    async fn stat(&self, path: &str, args: OpStat) -> Result<RpStat> {
+        let metadata = self.inner.stat(path, args).await?.into_metadata();
+
+        let metadata = match self.metadata.lock().unwrap().get(path) {
+            Some(user_metadata) => metadata.with_user_metadata(user_metadata.clone()),
+            None => metadata,
+        };
+
+        Ok(RpStat::new(metadata))
+    }
+
+    async fn delete(&self) -> Result<(RpDelete, Self::Deleter)> {
+        self.inner.delete().await
+    }
+
+    async fn list(&self, path: &str, args: OpList) -> Result<(RpList, Self::Lister)> {
+        self.inner.list(path, args).await
+    }
+}
+
+struct ObjectStoreWriter<W> {
+    inner: W,
+    path: String,
+    user_metadata: Option<HashMap<String, String>>,
+    metadata: Arc<Mutex<HashMap<String, HashMap<String, String>>>>,
+}
+
+impl<W: oio::Write> oio::Write for ObjectStoreWriter<W> {
+    async fn write(&mut self, bs: Buffer) -> Result<()> {
+        self.inner.write(bs).await
+    }
+
+    async fn close(&mut self) -> Result<Metadata> {
+        let metadata = self.inner.close().await?;
+        let mut stored = self.metadata.lock().unwrap();
+
+        match self.user_metadata.take() {
+            Some(user_metadata) => {
+                stored.insert(self.path.clone(), user_metadata.clone());
+
+                Ok(metadata.with_user_metadata(user_metadata))
+            }
+            None => {
+                stored.remove(&self.path);
+
+                Ok(metadata)
+            }
+        }
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.inner.abort().await
+    }
+}
+
+/// Request received by the test server
+pub(crate) struct Request {
+    /// Headers of the request with lowercase names
+    pub(crate) headers: HashMap<String, String>,
+}
+
+impl Request {
+    /// Byte range requested with a `Range: bytes=start-end` header (the end is `None` for open ranges)
+    pub(crate) fn range(&self) -> Option<(u64, Option<u64>)> {
+        let (start, end) = self
+            .headers
+            .get("range")?
+            .strip_prefix("bytes=")?
+            .split_once('-')?;
+
+        Some((start.parse().ok()?, end.parse().ok()))
+    }
+}
+
+/// Response sent by the test server
+pub(crate) struct Reply {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(&'static str, String)>,
+    pub(crate) body: Vec<u8>,
+    /// Number of body bytes sent before the connection is dropped (the whole body by default)
+    pub(crate) sent: Option<usize>,
+    /// Delay before the response is sent
+    pub(crate) delay: Duration,
+}
+
+impl Reply {
+    pub(crate) fn new(status: u16, body: &[u8]) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.to_vec(),
+            sent: None,
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+}
+
+/// Serve HTTP requests on a local port with one connection per request, returning the URL of a file on the server
+pub(crate) async fn serve(handler: impl Fn(&Request) -> Reply + Send + Sync + 'static) -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!("http://{}/file", listener.local_addr().unwrap())).unwrap();
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let handler = handler.clone();
+
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+
+                // The body of the request (if any) is ignored
+                let end = loop {
+                    if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end;
+                    }
+
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                };
+
+                head.truncate(end);
+
+                let headers = String::from_utf8_lossy(&head)
+                    .lines()
+                    .skip(1)
+                    .filter_map(|line| line.split_once(':'))
+                    .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string()))
+                    .collect();
+
+                let reply = handler(&Request { headers });
+
+                tokio::time::sleep(reply.delay).await;
+
+                let mut response = format!(
+                    "HTTP/1.1 {} \r\ncontent-length: {}\r\nconnection: close\r\n",
+                    reply.status,
+                    reply.body.len()
+                );
+
+                for (name, value) in &reply.headers {
+                    response.push_str(&format!("{}: {}\r\n", name, value));
+                }
+
+                response.push_str("\r\n");
+
+                let sent = reply.sent.unwrap_or(reply.body.len());
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.write_all(&reply.body[..sent]).await;
+                let _ = socket.shutdown().await;
+            });
+        }
+    });
+
+    url
+}
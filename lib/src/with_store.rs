@@ -2,7 +2,7 @@ use std::convert::TryFrom;
 
 use anyhow::Result;
 use opendal::Operator;
-use reqwest::Response;
+use reqwest::header::HeaderMap;
 use restate_sdk::prelude::*;
 use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize};
@@ -10,14 +10,24 @@ use typed_path::{UnixPath, UnixPathBuf};
 use url::Url;
 
 use crate::common::{
-    self, DownloadResponse, RequestOptions, filename_from_response, process_download, send_request,
+    self, DownloadBatchRequest, DownloadBatchResponse, DownloadOptions, DownloadResponse, Precheck,
+    RequestOptions, Target, await_workflow, batch_concurrency, cancel_workflow, check_target,
+    filename_from_response, precheck, process_download, run_batch, run_download, run_workflow,
+    send_request, terminal, workflow_status,
 };
+use crate::config::Config;
+use crate::digest::{Checksum, DigestAlgorithm, default_digests};
+use crate::notify::{CallbackOptions, Notifications, NotifyOptions};
+use crate::progress::DownloadStatus;
 
 /// Request to download a file from URL and save it to storage
 #[derive(Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 #[schemars(example = example_download_request())]
 pub struct DownloadRequest {
+    /// Download ID used to track progress in the progress object (progress is not tracked without it)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     /// URL to download from
     pub url: Url,
     /// Request options
@@ -26,13 +36,30 @@ pub struct DownloadRequest {
     /// Output options
     #[serde(skip_serializing_if = "Option::is_none")]
     pub output: Option<OutputOptions>,
+    /// Expected checksum of the downloaded file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<Checksum>,
+    /// Digest algorithms to compute for the downloaded file (defaults to sha256)
+    #[serde(default = "default_digests")]
+    pub digests: Vec<DigestAlgorithm>,
+    /// Webhook to notify when the download finishes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notify: Option<NotifyOptions>,
+    /// Restate handler to invoke with the result when the download finishes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback: Option<CallbackOptions>,
 }
 
 fn example_download_request() -> DownloadRequest {
     DownloadRequest {
+        id: None,
         url: Url::parse("https://example.com/file.pdf").unwrap(),
         request_options: None,
         output: None,
+        checksum: None,
+        digests: default_digests(),
+        notify: None,
+        callback: None,
     }
 }
 
@@ -58,44 +85,306 @@ pub trait Downloader {
     async fn download(
         request: Json<DownloadRequest>,
     ) -> Result<Json<DownloadResponse>, HandlerError>;
+
+    #[name = "downloadBatch"]
+    async fn download_batch(
+        request: Json<DownloadBatchRequest<DownloadRequest>>,
+    ) -> Result<Json<DownloadBatchResponse>, HandlerError>;
 }
 
+#[allow(dead_code)]
+const WORKFLOW_NAME: &str = match option_env!("RESTATE_WORKFLOW_NAME") {
+    Some(name) => name,
+    None => "DownloadWorkflow",
+};
+
+/// Download workflow keyed by download ID
+///
+/// `run` performs the download (tracking its progress under the workflow ID),
+/// while the shared handlers can be called by the same ID to inspect, await or cancel it.
+#[restate_sdk::workflow(name = WORKFLOW_NAME)]
+pub trait DownloadWorkflow {
+    async fn run(request: Json<DownloadRequest>) -> Result<Json<DownloadResponse>, HandlerError>;
+
+    #[shared]
+    async fn status() -> Result<Json<DownloadStatus>, HandlerError>;
+
+    #[shared]
+    #[name = "awaitCompletion"]
+    async fn await_completion() -> Result<Json<DownloadResponse>, HandlerError>;
+
+    #[shared]
+    async fn cancel() -> Result<Json<DownloadStatus>, HandlerError>;
+}
+
+#[derive(Clone)]
 pub struct DownloaderImpl {
     client: reqwest::Client,
+    ingress_client: reqwest::Client,
     operator: Operator,
+    config: Config,
 }
 
 impl DownloaderImpl {
     pub fn new(client: reqwest::Client, operator: Operator) -> Self {
-        Self { client, operator }
+        Self {
+            ingress_client: client.clone(),
+            client,
+            operator,
+            config: Config::default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Use a separate client to report progress to the Restate ingress
+    /// (the download client may not be allowed to reach it under the egress policy)
+    pub fn with_ingress_client(mut self, client: reqwest::Client) -> Self {
+        self.ingress_client = client;
+        self
+    }
+
+    fn notifications(&self, request: &DownloadRequest) -> Notifications {
+        Notifications::new(
+            &self.client,
+            &self.config,
+            request.id.as_deref(),
+            &request.url,
+            request.notify.as_ref(),
+            request.callback.as_ref(),
+        )
+    }
+
+    /// Determine the tenant of an invocation from its headers
+    fn tenant(
+        &self,
+        headers: &restate_sdk::context::HeaderMap,
+    ) -> Result<Option<String>, HandlerError> {
+        let Some(header) = &self.config.tenant_header else {
+            return Ok(None);
+        };
+
+        let tenant = headers
+            .get(header.as_str())
+            .ok_or_else(|| terminal(format!("Missing tenant header {}", header)))?;
+
+        check_segment(tenant).map_err(|err| terminal(format!("Invalid tenant: {}", err)))?;
+
+        Ok(Some(tenant.clone()))
     }
 
-    async fn _download(&self, request: DownloadRequest) -> Result<DownloadResponse, HandlerError> {
-        let response = send_request(&self.client, request.url, request.request_options).await?;
+    /// Directory paths are confined to: the root prefix and the directory of the tenant (eg. "downloads/acme/")
+    fn root(&self, tenant: Option<&str>) -> String {
+        [self.config.root_prefix.as_deref(), tenant]
+            .into_iter()
+            .flatten()
+            .map(|p| p.trim_matches('/'))
+            .filter(|p| !p.is_empty())
+            .map(|p| format!("{}/", p))
+            .collect()
+    }
+
+    /// Confine a resolved path to the root prefix and the directory of the tenant
+    fn confine(&self, path: String, tenant: Option<&str>) -> String {
+        format!("{}{}", self.root(tenant), path)
+    }
+
+    /// Download a file for an invocation, confining it to the directory of the tenant from the invocation headers
+    ///
+    /// The tenant is resolved within the download, so an invalid tenant fails the download like any other error
+    /// (reporting the failure and completing a workflow with it).
+    async fn download_for(
+        &self,
+        request: DownloadRequest,
+        headers: &restate_sdk::context::HeaderMap,
+        token: Option<String>,
+    ) -> Result<DownloadResponse, HandlerError> {
+        let tenant = self.tenant(headers)?;
+
+        self._download(request, tenant, token).await
+    }
+
+    async fn _download(
+        &self,
+        request: DownloadRequest,
+        tenant: Option<String>,
+        token: Option<String>,
+    ) -> Result<DownloadResponse, HandlerError> {
+        let output_path = request.output.as_ref().and_then(|o| o.path.clone());
+        let output = request.output.as_ref().map(|o| &o.common);
+
+        let headers = match output.filter(|o| o.needs_precheck()) {
+            Some(output) => {
+                let path = resolve_file_path(output_path.as_ref())?
+                    .map(|path| self.confine(output.path(path), tenant.as_deref()));
+
+                match precheck(&self.operator, path.as_deref(), output, token.as_deref()).await? {
+                    Precheck::Send(headers) => headers,
+                    Precheck::Skip(response) => return Ok(response),
+                }
+            }
+            None => HeaderMap::new(),
+        };
+
+        let response = send_request(
+            &self.client,
+            &self.config.egress,
+            request.url.clone(),
+            request.request_options.clone(),
+            headers,
+        )
+        .await?;
+
+        let path = resolve_path(output_path, || {
+            filename_from_response(&response, &self.config.filename).map(|f| match output {
+                Some(output) => output.filename(f),
+                None => f,
+            })
+        })?;
+        let path = match output {
+            Some(output) => output.path(path),
+            None => path,
+        };
+        let path = self.confine(path, tenant.as_deref());
+
+        let on_exists = output.map(|o| o.on_exists).unwrap_or_default();
 
-        let path = resolve_path(request.output.clone().and_then(|o| o.path), &response)?;
+        let path = match check_target(&self.operator, &response, path, on_exists, token.as_deref())
+            .await?
+        {
+            Target::Write(path) => path,
+            Target::Skip(response) => return Ok(response),
+        };
 
-        let size = process_download(
+        let file = process_download(
+            &self.client,
+            &self.ingress_client,
             &self.operator,
             response,
             path.as_str(),
-            request.output.map(|o| o.common),
+            DownloadOptions {
+                id: request.id,
+                url: Some(request.url),
+                root: Some(self.root(tenant.as_deref())),
+                request: request.request_options,
+                output: request.output.map(|o| o.common),
+                checksum: request.checksum,
+                digests: request.digests,
+                token,
+            },
+            &self.config,
+            &|_| Ok(()),
         )
         .await?;
 
-        Ok(DownloadResponse { path, size })
+        Ok(DownloadResponse {
+            path: file.path,
+            size: file.size,
+            digests: file.digests,
+            unchanged: false,
+            skipped: false,
+            entries: file.entries,
+        })
     }
 }
 
 impl Downloader for DownloaderImpl {
     async fn download(
+        &self,
+        mut ctx: Context<'_>,
+        request: Json<DownloadRequest>,
+    ) -> Result<Json<DownloadResponse>, HandlerError> {
+        let request = request.into_inner();
+        let token = ctx.rand_uuid().to_string();
+        let id = request.id.clone();
+        let notifications = self.notifications(&request);
+
+        run_download(
+            &ctx,
+            id.as_deref(),
+            self.config.ingress_url.is_some(),
+            notifications,
+            self.download_for(request, ctx.headers(), Some(token)),
+        )
+        .await
+    }
+
+    async fn download_batch(
         &self,
         ctx: Context<'_>,
+        request: Json<DownloadBatchRequest<DownloadRequest>>,
+    ) -> Result<Json<DownloadBatchResponse>, HandlerError> {
+        let request = request.into_inner();
+
+        let concurrency = batch_concurrency(request.concurrency, &self.config);
+
+        // Forward the tenant, so every download is confined to the same directory
+        let tenant = self
+            .config
+            .tenant_header
+            .as_ref()
+            .zip(self.tenant(ctx.headers())?);
+
+        // Every download is a separate invocation, so it is retried and journaled independently
+        let results = run_batch(request.downloads, concurrency, |download| {
+            let mut call = ctx
+                .service_client::<DownloaderClient>()
+                .download(Json(download));
+
+            if let Some((header, tenant)) = &tenant {
+                call = call.header(header.to_string(), tenant.to_string());
+            }
+
+            call.call()
+        })
+        .await;
+
+        Ok(Json(DownloadBatchResponse { results }))
+    }
+}
+
+impl DownloadWorkflow for DownloaderImpl {
+    async fn run(
+        &self,
+        mut ctx: WorkflowContext<'_>,
         request: Json<DownloadRequest>,
     ) -> Result<Json<DownloadResponse>, HandlerError> {
-        Ok(ctx
-            .run(async || self._download(request.into_inner()).await.map(Json))
-            .await?)
+        let mut request = request.into_inner();
+        let token = ctx.rand_uuid().to_string();
+        request.id = Some(ctx.key().to_string());
+        let notifications = self.notifications(&request);
+
+        run_workflow(
+            &ctx,
+            self.config.ingress_url.is_some(),
+            notifications,
+            self.download_for(request, ctx.headers(), Some(token)),
+        )
+        .await
+    }
+
+    async fn status(
+        &self,
+        ctx: SharedWorkflowContext<'_>,
+    ) -> Result<Json<DownloadStatus>, HandlerError> {
+        workflow_status(&ctx).await
+    }
+
+    async fn await_completion(
+        &self,
+        ctx: SharedWorkflowContext<'_>,
+    ) -> Result<Json<DownloadResponse>, HandlerError> {
+        await_workflow(&ctx).await
+    }
+
+    async fn cancel(
+        &self,
+        ctx: SharedWorkflowContext<'_>,
+    ) -> Result<Json<DownloadStatus>, HandlerError> {
+        cancel_workflow(&ctx).await
     }
 }
 
@@ -132,24 +421,71 @@ impl<'de> Deserialize<'de> for PosixPath {
     }
 }
 
-fn resolve_path(path: Option<PosixPath>, response: &Response) -> Result<String> {
+fn resolve_path(
+    path: Option<PosixPath>,
+    filename: impl FnOnce() -> Result<String, HandlerError>,
+) -> Result<String, HandlerError> {
+    if let Some(path) = resolve_file_path(path.as_ref())? {
+        return Ok(path);
+    }
+
+    let filename = filename()?;
+
+    check_segment(&filename)
+        .map_err(|err| terminal(format!("Invalid filename {:?}: {}", filename, err)))?;
+
+    match path {
+        Some(path) => Ok(path.as_unix_path().normalize().join(filename).to_string()),
+        None => Ok(filename),
+    }
+}
+
+/// Resolve a path naming a file (`None` when it names a directory the filename is appended to)
+fn resolve_file_path(path: Option<&PosixPath>) -> Result<Option<String>, HandlerError> {
     let Some(path) = path else {
-        return filename_from_response(response);
+        return Ok(None);
     };
 
     let unix_path = path.as_unix_path();
+
+    if unix_path.is_absolute() {
+        return Err(terminal(format!(
+            "Absolute paths are not allowed: {}",
+            path.0
+        )));
+    }
+
     let has_trailing_slash = unix_path.to_string().ends_with('/');
     let normalized = unix_path.normalize();
 
     if has_trailing_slash || normalized.to_string().is_empty() {
-        let filename = filename_from_response(response)?;
-
-        Ok(normalized.join(filename).to_string())
+        Ok(None)
     } else {
-        Ok(normalized.to_string())
+        Ok(Some(normalized.to_string()))
     }
 }
 
+/// Check that a filename or tenant ID is a single path segment
+fn check_segment(segment: &str) -> Result<(), &'static str> {
+    if segment.trim().is_empty() {
+        return Err("is empty");
+    }
+
+    if segment.contains(['/', '\\']) {
+        return Err("contains a path separator");
+    }
+
+    if segment.chars().any(char::is_control) {
+        return Err("contains a control character");
+    }
+
+    if segment == "." || segment == ".." {
+        return Err("refers to a directory");
+    }
+
+    Ok(())
+}
+
 /// Test module for resolve_filepath function and related utilities
 #[cfg(test)]
 mod tests {
@@ -179,6 +515,7 @@ mod tests {
         }
 
         // Helper that extracts filename similar to filename_from_response
+        #[allow(clippy::collapsible_if, clippy::double_ended_iterator_last)]
         fn extract_filename(&self) -> Result<String> {
             // First try content-disposition header
             if let Some(cd) = self.headers.get("content-disposition") {
@@ -198,26 +535,19 @@ mod tests {
         }
     }
 
-    /// Test helper function that mimics resolve_filepath but uses our mock data.
+    fn downloader(config: Config) -> DownloaderImpl {
+        DownloaderImpl::new(reqwest::Client::new(), crate::testing::operator()).with_config(config)
+    }
+
+    /// Test helper function that calls resolve_path with our mock data.
     /// This allows us to test the core logic without dealing with reqwest::Response mocking complexities.
     fn test_resolve_filepath(
         path: Option<PosixPath>,
         mock_response: &MockResponseData,
-    ) -> Result<String> {
-        let Some(path) = path else {
-            return mock_response.extract_filename();
-        };
-
-        let unix_path = path.as_unix_path();
-        let has_trailing_slash = unix_path.to_string().ends_with('/');
-        let normalized = unix_path.normalize();
-
-        if has_trailing_slash || normalized.to_string().is_empty() {
-            let filename = mock_response.extract_filename()?;
-            Ok(normalized.join(filename).to_string())
-        } else {
-            Ok(normalized.to_string())
-        }
+    ) -> Result<String, HandlerError> {
+        resolve_path(path, || {
+            mock_response.extract_filename().map_err(HandlerError::from)
+        })
     }
 
     /// Test that when path is None, the filename is extracted from the URL
@@ -305,7 +635,6 @@ mod tests {
             ("./downloads/file.txt", "downloads/file.txt"),
             ("downloads/../downloads/file.txt", "downloads/file.txt"),
             ("downloads/./file.txt", "downloads/file.txt"),
-            ("/absolute/path.txt", "/absolute/path.txt"),
             ("nested/../../file.txt", "file.txt"), // This normalizes to just "file.txt", not "../file.txt"
         ];
 
@@ -324,6 +653,195 @@ mod tests {
         }
     }
 
+    /// Test that absolute paths are rejected, so callers cannot escape the root prefix
+    #[test]
+    fn test_resolve_filepath_with_absolute_path() {
+        let test_cases = vec!["/absolute/path.txt", "/", "/downloads/"];
+
+        for input_path in test_cases {
+            let path = PosixPath(input_path.to_string());
+            let mock_response = MockResponseData::new("https://example.com/file.pdf");
+
+            let result = test_resolve_filepath(Some(path), &mock_response);
+            assert!(result.is_err(), "Should fail for input: {}", input_path);
+        }
+    }
+
+    /// Test that only paths naming a file resolve before the response is available
+    #[test]
+    fn test_resolve_file_path() {
+        let test_cases = vec![
+            (None, None),
+            (Some("downloads/file.pdf"), Some("downloads/file.pdf")),
+            (Some("./downloads/../file.pdf"), Some("file.pdf")),
+            (Some("downloads/"), None),
+            (Some("."), None),
+        ];
+
+        for (input_path, expected) in test_cases {
+            let path = input_path.map(|p| PosixPath(p.to_string()));
+
+            assert_eq!(
+                resolve_file_path(path.as_ref()).unwrap().as_deref(),
+                expected,
+                "Failed for input: {:?}",
+                input_path
+            );
+        }
+
+        assert!(resolve_file_path(Some(&PosixPath("/file.pdf".to_string()))).is_err());
+    }
+
+    /// Test that filenames from the response that are not a single path segment are rejected
+    #[test]
+    fn test_resolve_filepath_with_invalid_content_disposition() {
+        let test_cases = vec![
+            "attachment; filename=\"../../etc/passwd\"",
+            "attachment; filename=\"nested/file.txt\"",
+            "attachment; filename=\"nested\\\\file.txt\"",
+            "attachment; filename*=UTF-8''evil%0Aname.txt",
+            "attachment; filename=\"..\"",
+        ];
+
+        for cd_header in test_cases {
+            let path = PosixPath("output/".to_string());
+            let mock_response = MockResponseData::new("https://example.com/generic")
+                .with_content_disposition(cd_header);
+
+            let result = test_resolve_filepath(Some(path), &mock_response);
+            assert!(
+                result.is_err(),
+                "Should fail for content-disposition: {}",
+                cd_header
+            );
+        }
+    }
+
+    /// Test confining paths to the root prefix and the directory of the tenant
+    #[test]
+    fn test_confine() {
+        let test_cases = vec![
+            (None, None, "file.txt"),
+            (Some("downloads"), None, "downloads/file.txt"),
+            (Some("/downloads/"), None, "downloads/file.txt"),
+            (None, Some("acme"), "acme/file.txt"),
+            (Some("downloads/"), Some("acme"), "downloads/acme/file.txt"),
+        ];
+
+        for (root_prefix, tenant, expected) in test_cases {
+            let downloader = downloader(Config {
+                root_prefix: root_prefix.map(String::from),
+                ..Default::default()
+            });
+
+            assert_eq!(
+                downloader.confine("file.txt".to_string(), tenant),
+                expected,
+                "root prefix: {:?}, tenant: {:?}",
+                root_prefix,
+                tenant
+            );
+        }
+    }
+
+    /// Test that staged files are written inside the confined directory
+    #[test]
+    fn test_confine_staged() {
+        let downloader = downloader(Config {
+            root_prefix: Some("downloads/".to_string()),
+            ..Default::default()
+        });
+
+        let root = downloader.root(Some("acme"));
+        let path = downloader.confine("dir/file.txt".to_string(), Some("acme"));
+
+        let test_cases = vec![
+            (".incoming/", "downloads/acme/.incoming/dir/file.txt"),
+            ("otherTenant/", "downloads/acme/otherTenant/dir/file.txt"),
+        ];
+
+        for (prefix, expected) in test_cases {
+            assert_eq!(
+                common::staging_path(&root, prefix, &path).unwrap(),
+                expected,
+                "prefix: {}",
+                prefix
+            );
+        }
+
+        for prefix in ["../../", "/otherTenant/", "../otherTenant/"] {
+            assert!(
+                common::staging_path(&root, prefix, &path).is_err(),
+                "Should fail for prefix: {}",
+                prefix
+            );
+        }
+    }
+
+    /// Test that tenant IDs are read from the configured header and validated
+    #[test]
+    fn test_tenant() {
+        let tenant_downloader = downloader(Config {
+            tenant_header: Some("x-tenant-id".to_string()),
+            ..Default::default()
+        });
+
+        let test_cases = vec![
+            (Some("acme"), Some("acme")),
+            (Some(".."), None),
+            (Some("a/b"), None),
+            (Some(""), None),
+            (Some("  "), None),
+            (None, None),
+        ];
+
+        for (header, expected) in test_cases {
+            let mut headers = restate_sdk::context::HeaderMap::default();
+
+            if let Some(header) = header {
+                headers.insert("x-tenant-id", header.to_string());
+            }
+
+            assert_eq!(
+                tenant_downloader.tenant(&headers).ok().flatten().as_deref(),
+                expected,
+                "header: {:?}",
+                header
+            );
+        }
+
+        assert!(matches!(
+            downloader(Config::default()).tenant(&restate_sdk::context::HeaderMap::default()),
+            Ok(None)
+        ));
+    }
+
+    /// Test that a missing tenant fails the download itself, so the failure completes the workflow
+    #[test]
+    fn test_download_for_without_tenant() {
+        let downloader = downloader(Config {
+            tenant_header: Some("x-tenant-id".to_string()),
+            ..Default::default()
+        });
+
+        let request = DownloadRequest {
+            id: Some("abc".to_string()),
+            ..example_download_request()
+        };
+
+        let err = futures::executor::block_on(downloader.download_for(
+            request,
+            &restate_sdk::context::HeaderMap::default(),
+            None,
+        ))
+        .unwrap_err();
+
+        assert_eq!(
+            crate::testing::terminal_message(&err).as_deref(),
+            Some("Missing tenant header x-tenant-id")
+        );
+    }
+
     /// Test various directory paths with trailing slashes to ensure
     /// they are correctly joined with filenames from responses
     #[test]
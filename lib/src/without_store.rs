@@ -1,22 +1,33 @@
 use std::convert::TryFrom;
 
 use anyhow::{Context as AnyhowContext, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
 use opendal::{Operator, layers::LoggingLayer};
+use reqwest::header::HeaderMap;
 use restate_sdk::prelude::*;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::common::{
-    self, DownloadResponse, RequestOptions, filename_from_response, process_download, send_request,
-    terminal,
+    self, DownloadBatchRequest, DownloadBatchResponse, DownloadOptions, DownloadResponse, Precheck,
+    RequestOptions, Target, await_workflow, batch_concurrency, cancel_workflow, check_target,
+    filename_from_response, precheck, process_download, run_batch, run_download, run_workflow,
+    send_request, terminal, workflow_status,
 };
+use crate::config::Config;
+use crate::digest::{Checksum, DigestAlgorithm, default_digests};
+use crate::notify::{CallbackOptions, Notifications, NotifyOptions};
+use crate::progress::DownloadStatus;
 
 /// Request to download a file from URL and save it to storage
 #[derive(Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 #[schemars(example = example_download_request())]
 pub struct DownloadRequest {
+    /// Download ID used to track progress in the progress object (progress is not tracked without it)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     /// URL to download from
     pub url: Url,
     /// Request options
@@ -24,10 +35,23 @@ pub struct DownloadRequest {
     pub request_options: Option<RequestOptions>,
     /// Output options
     pub output: OutputOptions,
+    /// Expected checksum of the downloaded file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<Checksum>,
+    /// Digest algorithms to compute for the downloaded file (defaults to sha256)
+    #[serde(default = "default_digests")]
+    pub digests: Vec<DigestAlgorithm>,
+    /// Webhook to notify when the download finishes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notify: Option<NotifyOptions>,
+    /// Restate handler to invoke with the result when the download finishes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback: Option<CallbackOptions>,
 }
 
 fn example_download_request() -> DownloadRequest {
     DownloadRequest {
+        id: None,
         url: Url::parse(
             "https://download.blender.org/peach/bigbuckbunny_movies/big_buck_bunny_1080p_h264.mov",
         )
@@ -35,11 +59,12 @@ fn example_download_request() -> DownloadRequest {
         request_options: None,
         output: OutputOptions {
             uri: Url::parse("s3://bucket").unwrap(),
-            common: common::OutputOptions {
-                set_content_type: false,
-                content_type: None,
-            },
+            common: common::OutputOptions::default(),
         },
+        checksum: None,
+        digests: default_digests(),
+        notify: None,
+        callback: None,
     }
 }
 
@@ -63,72 +88,477 @@ pub trait Downloader {
     async fn download(
         request: Json<DownloadRequest>,
     ) -> Result<Json<DownloadResponse>, HandlerError>;
+
+    #[name = "downloadBatch"]
+    async fn download_batch(
+        request: Json<DownloadBatchRequest<DownloadRequest>>,
+    ) -> Result<Json<DownloadBatchResponse>, HandlerError>;
+}
+
+#[allow(dead_code)]
+const WORKFLOW_NAME: &str = match option_env!("RESTATE_WORKFLOW_NAME") {
+    Some(name) => name,
+    None => "DownloadWorkflow",
+};
+
+/// Download workflow keyed by download ID
+///
+/// `run` performs the download (tracking its progress under the workflow ID),
+/// while the shared handlers can be called by the same ID to inspect, await or cancel it.
+#[restate_sdk::workflow(name = WORKFLOW_NAME)]
+pub trait DownloadWorkflow {
+    async fn run(request: Json<DownloadRequest>) -> Result<Json<DownloadResponse>, HandlerError>;
+
+    #[shared]
+    async fn status() -> Result<Json<DownloadStatus>, HandlerError>;
+
+    #[shared]
+    #[name = "awaitCompletion"]
+    async fn await_completion() -> Result<Json<DownloadResponse>, HandlerError>;
+
+    #[shared]
+    async fn cancel() -> Result<Json<DownloadStatus>, HandlerError>;
 }
 
+#[derive(Clone)]
 pub struct DownloaderImpl {
     client: reqwest::Client,
+    ingress_client: reqwest::Client,
+    config: Config,
+    storage_uris: StorageUris,
 }
 
 impl DownloaderImpl {
     pub fn new(client: reqwest::Client) -> Self {
-        Self { client }
+        Self {
+            ingress_client: client.clone(),
+            client,
+            config: Config::default(),
+            storage_uris: Ok(None),
+        }
     }
 
-    async fn _download(&self, request: DownloadRequest) -> Result<DownloadResponse, HandlerError> {
-        let response = send_request(&self.client, request.url, request.request_options).await?;
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.storage_uris = compile_storage_uris(&config.allowed_storage_uris);
+        self.config = config;
+        self
+    }
 
-        let (uri, path) = resolve_uri_and_path(request.output.uri, &response)?;
+    /// Use a separate client to report progress to the Restate ingress
+    /// (the download client may not be allowed to reach it under the egress policy)
+    pub fn with_ingress_client(mut self, client: reqwest::Client) -> Self {
+        self.ingress_client = client;
+        self
+    }
+
+    fn notifications(&self, request: &DownloadRequest) -> Notifications {
+        Notifications::new(
+            &self.client,
+            &self.config,
+            request.id.as_deref(),
+            &request.url,
+            request.notify.as_ref(),
+            request.callback.as_ref(),
+        )
+    }
+
+    async fn _download(
+        &self,
+        request: DownloadRequest,
+        token: Option<String>,
+    ) -> Result<DownloadResponse, HandlerError> {
+        let output = &request.output.common;
+        let (uri, filename) = split_uri(request.output.uri.clone())?;
+
+        // A storage URI naming a directory only determines the path once the response provides the filename
+        let file_path = filename.map(|filename| output.path(filename));
+
+        if let Some(path) = &file_path {
+            check_storage_uri(&self.storage_uris, &uri, path)?;
+        }
 
         let operator = Operator::from_uri(uri.as_str())
             .context("Failed to create operator from config")
             .map_err(terminal)?
             .layer(LoggingLayer::default());
 
-        let size = process_download(
+        let headers = if output.needs_precheck() {
+            match precheck(&operator, file_path.as_deref(), output, token.as_deref()).await? {
+                Precheck::Send(headers) => headers,
+                Precheck::Skip(response) => return Ok(response),
+            }
+        } else {
+            HeaderMap::new()
+        };
+
+        let response = send_request(
+            &self.client,
+            &self.config.egress,
+            request.url.clone(),
+            request.request_options.clone(),
+            headers,
+        )
+        .await?;
+
+        let path = match file_path {
+            Some(path) => path,
+            None => {
+                let filename = filename_from_response(&response, &self.config.filename)?;
+                let path = output.path(output.filename(filename));
+
+                check_storage_uri(&self.storage_uris, &uri, &path)?;
+
+                path
+            }
+        };
+
+        let path = match check_target(
+            &operator,
+            &response,
+            path,
+            output.on_exists,
+            token.as_deref(),
+        )
+        .await?
+        {
+            Target::Write(path) => path,
+            Target::Skip(response) => return Ok(response),
+        };
+
+        let file = process_download(
+            &self.client,
+            &self.ingress_client,
             &operator,
             response,
             path.as_str(),
-            Some(request.output.common),
+            DownloadOptions {
+                id: request.id,
+                url: Some(request.url),
+                root: None,
+                request: request.request_options,
+                output: Some(request.output.common),
+                checksum: request.checksum,
+                digests: request.digests,
+                token,
+            },
+            &self.config,
+            &|path| check_storage_uri(&self.storage_uris, &uri, path),
         )
         .await?;
 
-        Ok(DownloadResponse { path, size })
+        Ok(DownloadResponse {
+            path: file.path,
+            size: file.size,
+            digests: file.digests,
+            unchanged: false,
+            skipped: false,
+            entries: file.entries,
+        })
     }
 }
 
-fn resolve_uri_and_path(
-    mut uri: Url,
-    response: &reqwest::Response,
-) -> Result<(Url, String), HandlerError> {
-    let path = if uri.path().is_empty() || uri.path().ends_with('/') {
-        filename_from_response(response)?
-    } else {
-        let path = uri
-            .path_segments()
-            .and_then(|mut s| s.next_back())
-            .filter(|s| !s.is_empty())
-            .map(String::from)
-            .unwrap_or_else(|| "download".into());
-
-        uri.path_segments_mut()
-            .map_err(|_| anyhow::anyhow!("Cannot modify URL path"))
-            .map_err(terminal)?
-            .pop();
+/// Split a storage URI naming a file into the URI of its directory and the filename
+/// (the filename is `None` when the URI names a directory)
+fn split_uri(mut uri: Url) -> Result<(Url, Option<String>), HandlerError> {
+    if !names_file(&uri) {
+        return Ok((uri, None));
+    }
+
+    let filename = uri
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .unwrap_or_else(|| "download".into());
 
-        path
+    uri.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Cannot modify URL path"))
+        .map_err(terminal)?
+        .pop();
+
+    Ok((uri, Some(filename)))
+}
+
+/// Check whether a storage URI names a file (otherwise the filename is appended to it)
+fn names_file(uri: &Url) -> bool {
+    !uri.path().is_empty() && !uri.path().ends_with('/')
+}
+
+/// Storage URIs callers may write to (`None` allows any) or the error compiling the patterns
+type StorageUris = Result<Option<GlobSet>, String>;
+
+/// Compile the storage URI patterns of the config into a single matcher
+fn compile_storage_uris(patterns: &[String]) -> StorageUris {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|err| format!("Invalid storage URI pattern {}: {}", pattern, err))?;
+
+        builder.add(glob);
+    }
+
+    builder.build().map(Some).map_err(|err| err.to_string())
+}
+
+/// Check that callers may write to the target URI of a download
+fn check_storage_uri(
+    storage_uris: &StorageUris,
+    uri: &Url,
+    path: &str,
+) -> Result<(), HandlerError> {
+    let Some(matcher) = storage_uris.as_ref().map_err(terminal)? else {
+        return Ok(());
     };
 
-    Ok((uri, path))
+    if uri.query().is_some() {
+        return Err(terminal(
+            "Query parameters are not allowed in the storage URI",
+        ));
+    }
+
+    let mut base = uri.clone();
+    base.set_fragment(None);
+
+    let target = format!(
+        "{}/{}",
+        base.as_str().trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+
+    if target.split('/').any(|segment| segment == "..") {
+        return Err(terminal(format!(
+            "Storage URI {} must not contain '..' segments",
+            target
+        )));
+    }
+
+    if matcher.is_match(&target) {
+        return Ok(());
+    }
+
+    Err(terminal(format!(
+        "Writing to storage URI {} is not allowed",
+        target
+    )))
 }
 
 impl Downloader for DownloaderImpl {
     async fn download(
+        &self,
+        mut ctx: Context<'_>,
+        request: Json<DownloadRequest>,
+    ) -> Result<Json<DownloadResponse>, HandlerError> {
+        let request = request.into_inner();
+        let token = ctx.rand_uuid().to_string();
+        let id = request.id.clone();
+        let notifications = self.notifications(&request);
+
+        run_download(
+            &ctx,
+            id.as_deref(),
+            self.config.ingress_url.is_some(),
+            notifications,
+            self._download(request, Some(token)),
+        )
+        .await
+    }
+
+    async fn download_batch(
         &self,
         ctx: Context<'_>,
+        request: Json<DownloadBatchRequest<DownloadRequest>>,
+    ) -> Result<Json<DownloadBatchResponse>, HandlerError> {
+        let request = request.into_inner();
+
+        let concurrency = batch_concurrency(request.concurrency, &self.config);
+
+        // Every download is a separate invocation, so it is retried and journaled independently
+        let results = run_batch(request.downloads, concurrency, |download| {
+            ctx.service_client::<DownloaderClient>()
+                .download(Json(download))
+                .call()
+        })
+        .await;
+
+        Ok(Json(DownloadBatchResponse { results }))
+    }
+}
+
+impl DownloadWorkflow for DownloaderImpl {
+    async fn run(
+        &self,
+        mut ctx: WorkflowContext<'_>,
         request: Json<DownloadRequest>,
     ) -> Result<Json<DownloadResponse>, HandlerError> {
-        Ok(ctx
-            .run(async || self._download(request.into_inner()).await.map(Json))
-            .await?)
+        let mut request = request.into_inner();
+        let token = ctx.rand_uuid().to_string();
+        request.id = Some(ctx.key().to_string());
+        let notifications = self.notifications(&request);
+
+        run_workflow(
+            &ctx,
+            self.config.ingress_url.is_some(),
+            notifications,
+            self._download(request, Some(token)),
+        )
+        .await
+    }
+
+    async fn status(
+        &self,
+        ctx: SharedWorkflowContext<'_>,
+    ) -> Result<Json<DownloadStatus>, HandlerError> {
+        workflow_status(&ctx).await
+    }
+
+    async fn await_completion(
+        &self,
+        ctx: SharedWorkflowContext<'_>,
+    ) -> Result<Json<DownloadResponse>, HandlerError> {
+        await_workflow(&ctx).await
+    }
+
+    async fn cancel(
+        &self,
+        ctx: SharedWorkflowContext<'_>,
+    ) -> Result<Json<DownloadStatus>, HandlerError> {
+        cancel_workflow(&ctx).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::egress::EgressPolicy;
+    use crate::testing::{self, Reply, terminal_message};
+
+    fn storage_uris(patterns: &[&str]) -> StorageUris {
+        compile_storage_uris(&patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+    }
+
+    /// Test checking the target of a download against the allowed storage URIs
+    #[test]
+    fn test_check_storage_uri() {
+        let patterns = storage_uris(&[
+            "s3://bucket/exports/**",
+            "s3://uploads-*/*",
+            "s3://bucket/csv/*.csv",
+        ]);
+
+        let cases = [
+            ("s3://bucket/exports/", "file.csv", true),
+            ("s3://bucket/exports/2024/01", "file.csv", true),
+            ("s3://uploads-team-a", "file.csv", true),
+            ("s3://uploads-team-a/nested", "file.csv", false),
+            ("s3://bucket/", "file.csv", false),
+            ("s3://bucket/exports-other/", "file.csv", false),
+            ("s3://other/exports/", "file.csv", false),
+            ("gcs://bucket/exports/", "file.csv", false),
+            ("s3://bucket/exports/", "../secret.csv", false),
+            ("s3://bucket/csv/", "file.csv", true),
+            ("s3://bucket/csv/", "nested/x.csv", false),
+            ("s3://bucket/csv/", ".incoming/file.csv", false),
+            ("s3://bucket/csv/", "file.csv.meta.json", false),
+            ("s3://bucket/exports/../secret/", "file.csv", false),
+            (
+                "s3://bucket/exports/?endpoint=http://evil",
+                "file.csv",
+                false,
+            ),
+        ];
+
+        for (uri, path, expected) in cases {
+            assert_eq!(
+                check_storage_uri(&patterns, &Url::parse(uri).unwrap(), path).is_ok(),
+                expected,
+                "uri: {}, path: {}",
+                uri,
+                path
+            );
+        }
+
+        assert!(
+            check_storage_uri(&Ok(None), &Url::parse("s3://any?x=1").unwrap(), "file.csv").is_ok()
+        );
+
+        let invalid = storage_uris(&["s3://bucket/[csv"]);
+
+        assert!(
+            check_storage_uri(&invalid, &Url::parse("s3://bucket/").unwrap(), "file.csv").is_err()
+        );
+    }
+
+    /// Test splitting the storage URI of a file into its directory and the filename
+    #[test]
+    fn test_split_uri() {
+        let cases = [
+            (
+                "s3://bucket/csv/report.csv",
+                "s3://bucket/csv",
+                Some("report.csv"),
+            ),
+            ("s3://bucket/csv/", "s3://bucket/csv/", None),
+            ("s3://bucket", "s3://bucket", None),
+        ];
+
+        for (uri, expected_uri, expected_filename) in cases {
+            let (uri, filename) = split_uri(Url::parse(uri).unwrap()).unwrap();
+
+            assert_eq!(uri.as_str(), expected_uri);
+            assert_eq!(filename.as_deref(), expected_filename);
+        }
+    }
+
+    /// Test that a storage URI naming a directory is checked against the filename from the response
+    #[tokio::test]
+    async fn test_download_to_directory() {
+        let downloader = DownloaderImpl::new(reqwest::Client::new()).with_config(Config {
+            allowed_storage_uris: vec!["memory:///csv/*.csv".to_string()],
+            egress: EgressPolicy {
+                allowed_schemes: vec!["http".to_string()],
+                allow_private: true,
+                ..EgressPolicy::default()
+            },
+            ..Config::default()
+        });
+
+        let url = testing::serve(|_| {
+            Reply::new(200, b"a,b\n1,2\n")
+                .header("content-disposition", "attachment; filename=report.csv")
+        })
+        .await;
+
+        let request = |uri: &str| DownloadRequest {
+            url: url.join("export").unwrap(),
+            output: OutputOptions {
+                uri: Url::parse(uri).unwrap(),
+                common: common::OutputOptions::default(),
+            },
+            ..example_download_request()
+        };
+
+        let response = downloader
+            ._download(request("memory:///csv/"), None)
+            .await
+            .unwrap();
+
+        assert_eq!(response.path, "report.csv");
+
+        let err = downloader
+            ._download(request("memory:///csv/report.txt"), None)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            terminal_message(&err).as_deref(),
+            Some("Writing to storage URI memory:///csv/report.txt is not allowed")
+        );
     }
 }